@@ -0,0 +1,201 @@
+//! Minimal OpenID Connect provider for trying the `/auth/{provider}` login flow locally.
+//!
+//! It approves every authorization request without asking anything: the signed-in identity is
+//! taken from the `login_hint` parameter (`alice@example.com` by default). Run it with
+//!
+//! ```text
+//! cargo run --example mock_oidc
+//! ```
+//!
+//! and start the server with
+//!
+//! ```text
+//! OIDC_PROVIDERS=mock OIDC_MOCK_ISSUER=http://127.0.0.1:8082 OIDC_MOCK_CLIENT_ID=actixserver cargo run
+//! ```
+//!
+//! then open `http://127.0.0.1:8081/auth/mock/start?login_hint=bob@example.com`.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{App, HttpResponse, HttpServer, Responder, http::header, web};
+use argon2::password_hash::rand_core::OsRng;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{SigningKey, pkcs8::EncodePrivateKey};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const ADDRESS: (&str, u16) = ("127.0.0.1", 8082);
+const KID: &str = "mock-key";
+
+struct Provider {
+    issuer: String,
+    encoding: EncodingKey,
+    public_key: String,
+    codes: Mutex<HashMap<String, PendingCode>>,
+}
+
+struct PendingCode {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    nonce: Option<String>,
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: Option<String>,
+    code_challenge: String,
+    code_challenge_method: String,
+    login_hint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    code_verifier: String,
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    iat: u64,
+    exp: u64,
+    nonce: Option<String>,
+    email: String,
+    email_verified: bool,
+    preferred_username: String,
+}
+
+async fn discovery(provider: web::Data<Provider>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "issuer": provider.issuer,
+        "authorization_endpoint": format!("{}/authorize", provider.issuer),
+        "token_endpoint": format!("{}/token", provider.issuer),
+        "jwks_uri": format!("{}/jwks", provider.issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+async fn jwks(provider: web::Data<Provider>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "alg": "EdDSA",
+            "use": "sig",
+            "kid": KID,
+            "x": provider.public_key,
+        }]
+    }))
+}
+
+async fn authorize(provider: web::Data<Provider>, query: web::Query<AuthorizeQuery>) -> impl Responder {
+    if query.code_challenge_method != "S256" {
+        return HttpResponse::BadRequest().body("only S256 code challenges are supported");
+    }
+    let query = query.into_inner();
+    let code = Uuid::new_v4().simple().to_string();
+    let location = format!(
+        "{}{}code={}&state={}",
+        query.redirect_uri,
+        if query.redirect_uri.contains('?') { '&' } else { '?' },
+        code,
+        query.state
+    );
+    provider.codes.lock().unwrap().insert(
+        code,
+        PendingCode {
+            client_id: query.client_id,
+            redirect_uri: query.redirect_uri,
+            code_challenge: query.code_challenge,
+            nonce: query.nonce,
+            email: query.login_hint.unwrap_or_else(|| "alice@example.com".to_string()),
+        },
+    );
+    HttpResponse::Found().insert_header((header::LOCATION, location)).finish()
+}
+
+async fn token(provider: web::Data<Provider>, form: web::Form<TokenForm>) -> impl Responder {
+    let invalid_grant = || HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+    if form.grant_type != "authorization_code" {
+        return HttpResponse::BadRequest().json(json!({ "error": "unsupported_grant_type" }));
+    }
+    let Some(pending) = provider.codes.lock().unwrap().remove(&form.code) else {
+        return invalid_grant();
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes()));
+    if pending.client_id != form.client_id
+        || pending.redirect_uri != form.redirect_uri
+        || pending.code_challenge != challenge
+    {
+        return invalid_grant();
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let claims = IdTokenClaims {
+        iss: provider.issuer.clone(),
+        sub: format!("mock|{}", pending.email),
+        aud: pending.client_id,
+        iat: now,
+        exp: now + 300,
+        nonce: pending.nonce,
+        preferred_username: pending.email.split('@').next().unwrap_or_default().to_string(),
+        email: pending.email,
+        email_verified: true,
+    };
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(KID.to_string());
+    match jsonwebtoken::encode(&header, &claims, &provider.encoding) {
+        Ok(id_token) => HttpResponse::Ok().json(json!({
+            "access_token": Uuid::new_v4().to_string(),
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token,
+        })),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let signing_key = SigningKey::generate(&mut OsRng);
+    let der = signing_key.to_pkcs8_der().map_err(std::io::Error::other)?;
+    let provider = web::Data::new(Provider {
+        issuer: format!("http://{}:{}", ADDRESS.0, ADDRESS.1),
+        encoding: EncodingKey::from_ed_der(der.as_bytes()),
+        public_key: URL_SAFE_NO_PAD.encode(signing_key.verifying_key().as_bytes()),
+        codes: Mutex::new(HashMap::new()),
+    });
+    println!("Mock OIDC provider listening on {}", provider.issuer);
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(provider.clone())
+            .route("/.well-known/openid-configuration", web::get().to(discovery))
+            .route("/jwks", web::get().to(jwks))
+            .route("/authorize", web::get().to(authorize))
+            .route("/token", web::post().to(token))
+    })
+    .bind(ADDRESS)?
+    .run()
+    .await
+}
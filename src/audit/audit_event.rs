@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::user::session::ClientInfo;
+
+/// Default and maximum number of events returned by one audit query.
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventType {
+    UserRegistered,
+    LoginSucceeded,
+    LoginFailed,
+    Logout,
+    PasswordChanged,
+    PasswordReset,
+    UsernameChanged,
+    EmailChangeRequested,
+    RoleGranted,
+    RoleRevoked,
+    AccountDeactivated,
+    AccountReactivated,
+    AccountDeleted,
+    AccountErased,
+    ProductDeleted,
+    PersonalDataExported,
+}
+
+/// One entry of the audit trail. The actor is the id of the user behind the request, if known;
+/// the target names the affected resource, e.g. `user:<id>` or `product:<id>`. Users are only
+/// ever referred to by id: usernames change and are anonymized on erasure, which this
+/// append-only trail could not follow.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub timestamp: DateTime<Utc>,
+    pub event: AuditEventType,
+    pub actor: Option<u32>,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Free-form context, such as the role granted or why a login failed.
+    pub details: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(event: AuditEventType, client: &ClientInfo) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            event,
+            actor: None,
+            target: None,
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            details: None,
+        }
+    }
+
+    pub fn actor(mut self, user_id: u32) -> Self {
+        self.actor = Some(user_id);
+        self
+    }
+
+    pub fn target_user(self, user_id: u32) -> Self {
+        self.target(format!("user:{}", user_id))
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+}
+
+/// Filters of `GET /audit`; every criterion is optional and they all have to match.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<u32>,
+    pub target: Option<String>,
+    pub event: Option<AuditEventType>,
+    /// Inclusive lower bound on the timestamp.
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive upper bound on the timestamp.
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.actor.is_none_or(|actor| event.actor == Some(actor))
+            && self.target.as_ref().is_none_or(|target| event.target.as_ref() == Some(target))
+            && self.event.is_none_or(|kind| event.event == kind)
+            && self.from.is_none_or(|from| event.timestamp >= from)
+            && self.to.is_none_or(|to| event.timestamp < to)
+    }
+
+    /// Requested number of events; the API caps it at `MAX_QUERY_LIMIT`.
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_QUERY_LIMIT)
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use actix_web::web;
+
+use crate::{
+    audit::{
+        Error::{self, StorageError},
+        Result,
+        audit_event::{AuditEvent, AuditQuery},
+    },
+    export::{self, personal_data::PersonalDataSource},
+    user::user_service::UserInfo,
+    utils::env::var_or,
+};
+
+/// Append-only store of audit events. Events are never updated nor deleted through it.
+#[async_trait::async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, event: AuditEvent) -> Result<()>;
+    /// Matching events, most recent first, up to the query's limit.
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>>;
+}
+
+/// Builds the sink selected by `AUDIT_SINK`: `memory` (default) keeps events for the lifetime of
+/// the process, `file` appends them as JSON lines to `AUDIT_FILE`.
+pub fn from_env() -> std::result::Result<Arc<dyn AuditSink>, String> {
+    match var_or("AUDIT_SINK", "memory".to_string()).as_str() {
+        "memory" => Ok(Arc::new(MemoryAuditSink::new())),
+        "file" => Ok(Arc::new(JsonLinesAuditSink::new(var_or(
+            "AUDIT_FILE",
+            PathBuf::from("audit.log"),
+        )))),
+        other => Err(format!("Unknown AUDIT_SINK '{}'", other)),
+    }
+}
+
+/// Records an event without failing the request it belongs to: a sink error is only logged.
+pub async fn record(sink: &dyn AuditSink, event: AuditEvent) {
+    if let Err(err) = sink.record(event).await {
+        eprintln!("Failed to record audit event: {:?}", err);
+    }
+}
+
+
+/// Exposes the events a user performed or was the target of to personal data exports. Events
+/// are matched by user id, so they survive renames; in events performed by someone else, the
+/// actor and their client are redacted as they are that other person's data.
+pub struct AuditExport {
+    sink: Arc<dyn AuditSink>,
+}
+
+impl AuditExport {
+    pub fn new(sink: Arc<dyn AuditSink>) -> Self {
+        Self { sink }
+    }
+}
+
+#[async_trait::async_trait]
+impl PersonalDataSource for AuditExport {
+    fn section(&self) -> &'static str {
+        "audit_events"
+    }
+
+    async fn export(&self, user: &UserInfo) -> export::Result<serde_json::Value> {
+        let source_error = |e: Error| export::Error::SourceError(format!("{:?}", e));
+        let as_actor = AuditQuery {
+            actor: Some(user.id),
+            limit: Some(usize::MAX),
+            ..AuditQuery::default()
+        };
+        let as_target = AuditQuery {
+            target: Some(format!("user:{}", user.id)),
+            limit: Some(usize::MAX),
+            ..AuditQuery::default()
+        };
+        let mut events = self.sink.query(&as_actor).await.map_err(source_error)?;
+        for event in self.sink.query(&as_target).await.map_err(source_error)? {
+            if !events.iter().any(|e| e.id == event.id) {
+                events.push(event);
+            }
+        }
+        for event in events.iter_mut().filter(|event| event.actor != Some(user.id)) {
+            event.actor = None;
+            event.ip = None;
+            event.user_agent = None;
+        }
+        events.sort_by_key(|event| std::cmp::Reverse(event.timestamp));
+        serde_json::to_value(events).map_err(|e| export::Error::SourceError(e.to_string()))
+    }
+}
+
+
+/////////// MemoryAuditSink //////////////////////////////////////////////////////////////////////////////////////
+
+pub struct MemoryAuditSink {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl MemoryAuditSink {
+    pub fn new() -> Self {
+        Self {
+            events: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for MemoryAuditSink {
+    async fn record(&self, event: AuditEvent) -> Result<()> {
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+        let events = self.events.lock().unwrap();
+        Ok(events
+            .iter()
+            .rev()
+            .filter(|event| query.matches(event))
+            .take(query.limit())
+            .cloned()
+            .collect())
+    }
+}
+
+
+/////////// JsonLinesAuditSink ///////////////////////////////////////////////////////////////////////////////////
+
+/// Appends one JSON object per line. Queries scan the whole file, which is fine for the volume
+/// of a single instance; ship the file to a log store for anything larger. File access runs on
+/// the blocking thread pool so that it never stalls the request workers.
+pub struct JsonLinesAuditSink {
+    path: PathBuf,
+    /// Serializes writers so concurrent events never interleave within a line.
+    lock: Arc<Mutex<()>>,
+}
+
+impl JsonLinesAuditSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditSink for JsonLinesAuditSink {
+    async fn record(&self, event: AuditEvent) -> Result<()> {
+        let line = serde_json::to_string(&event)
+            .map_err(|e| StorageError(format!("Cannot serialize audit event: {}", e)))?;
+        let path = self.path.clone();
+        let lock = self.lock.clone();
+        web::block(move || {
+            let _guard = lock.lock().unwrap();
+            append_line(&path, &line)
+        })
+        .await
+        .map_err(|e| StorageError(format!("Cannot write {}: {}", self.path.display(), e)))?
+    }
+
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+        let path = self.path.clone();
+        let query = query.clone();
+        web::block(move || scan(&path, &query))
+            .await
+            .map_err(|e| StorageError(format!("Cannot read {}: {}", self.path.display(), e)))?
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| StorageError(format!("Cannot open {}: {}", path.display(), e)))?;
+    writeln!(file, "{}", line).map_err(|e| StorageError(format!("Cannot write {}: {}", path.display(), e)))
+}
+
+/// Lines that do not parse, such as one cut short by a crash, are skipped.
+fn scan(path: &Path, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError(format!("Cannot open {}: {}", path.display(), e))),
+    };
+    let mut events = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| StorageError(format!("Cannot read {}: {}", path.display(), e)))?;
+        if let Ok(event) = serde_json::from_str::<AuditEvent>(&line)
+            && query.matches(&event)
+        {
+            events.push(event);
+        }
+    }
+    events.reverse();
+    events.truncate(query.limit());
+    Ok(events)
+}
@@ -0,0 +1,6 @@
+mod errors;
+
+pub mod audit_event;
+pub mod audit_sink;
+
+pub use self::errors::{Error, Result};
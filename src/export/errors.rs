@@ -0,0 +1,10 @@
+use serde::Serialize;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Serialize)]
+pub enum Error {
+    /// A subsystem could not provide its data.
+    SourceError(String),
+    ArchiveError(String),
+}
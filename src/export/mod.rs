@@ -0,0 +1,5 @@
+mod errors;
+
+pub mod personal_data;
+
+pub use self::errors::{Error, Result};
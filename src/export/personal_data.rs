@@ -0,0 +1,95 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Write},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    export::{Error::ArchiveError, Result},
+    user::user_service::UserInfo,
+};
+
+/// A subsystem holding personal data. Each one contributes a section to the export of a user,
+/// so a new module only has to implement this trait and be registered with the exporter.
+#[async_trait::async_trait]
+pub trait PersonalDataSource: Send + Sync {
+    /// Key of the section in the JSON document, and file name inside the zip archive.
+    fn section(&self) -> &'static str;
+    async fn export(&self, user: &UserInfo) -> Result<serde_json::Value>;
+}
+
+/// Collects the sections of every registered source into one document.
+#[derive(Clone, Default)]
+pub struct PersonalDataExporter {
+    sources: Vec<Arc<dyn PersonalDataSource>>,
+}
+
+impl PersonalDataExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_source(mut self, source: Arc<dyn PersonalDataSource>) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    pub async fn export(&self, user: &UserInfo) -> Result<PersonalDataExport> {
+        let mut sections = BTreeMap::new();
+        for source in &self.sources {
+            sections.insert(source.section(), source.export(user).await?);
+        }
+        Ok(PersonalDataExport {
+            exported_at: Utc::now(),
+            user_id: user.id,
+            sections,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct PersonalDataExport {
+    pub exported_at: DateTime<Utc>,
+    pub user_id: u32,
+    pub sections: BTreeMap<&'static str, serde_json::Value>,
+}
+
+impl PersonalDataExport {
+    /// Zip archive with a `manifest.json` describing the export and one JSON file per section.
+    pub fn to_zip(&self) -> Result<Vec<u8>> {
+        let archive_error = |e: &dyn std::fmt::Display| ArchiveError(format!("Cannot build archive: {}", e));
+        let manifest = serde_json::json!({
+            "exported_at": self.exported_at,
+            "user_id": self.user_id,
+            "sections": self.sections.keys().collect::<Vec<_>>(),
+        });
+        let files = std::iter::once(("manifest", &manifest)).chain(self.sections.iter().map(|(name, value)| (*name, value)));
+
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, value) in files {
+            let json = serde_json::to_vec_pretty(value).map_err(|e| archive_error(&e))?;
+            zip.start_file(format!("{}.json", name), options).map_err(|e| archive_error(&e))?;
+            zip.write_all(&json).map_err(|e| archive_error(&e))?;
+        }
+        Ok(zip.finish().map_err(|e| archive_error(&e))?.into_inner())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Zip,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
@@ -0,0 +1,96 @@
+use std::{fs::OpenOptions, io::Write, path::PathBuf, sync::Arc};
+
+use chrono::Utc;
+
+use crate::{
+    mail::{Error::DeliveryError, Result},
+    utils::env::var_or,
+};
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    fn render(&self, from: &str) -> String {
+        format!(
+            "Date: {}\nFrom: {}\nTo: {}\nSubject: {}\n\n{}\n",
+            Utc::now().to_rfc2822(),
+            from,
+            self.to,
+            self.subject,
+            self.body
+        )
+    }
+}
+
+/// Delivers transactional emails such as password reset links.
+#[async_trait::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<()>;
+}
+
+/// Builds the mailer selected by `MAILER`: `console` (default) prints messages to stdout,
+/// `file` appends them to `MAILER_FILE`. Both are meant for development.
+pub fn from_env() -> std::result::Result<Arc<dyn Mailer>, String> {
+    let from = var_or("MAIL_FROM", "no-reply@localhost".to_string());
+    match var_or("MAILER", "console".to_string()).as_str() {
+        "console" => Ok(Arc::new(ConsoleMailer::new(from))),
+        "file" => Ok(Arc::new(FileMailer::new(
+            from,
+            var_or("MAILER_FILE", PathBuf::from("mail.log")),
+        ))),
+        other => Err(format!("Unknown MAILER '{}'", other)),
+    }
+}
+
+
+/////////// ConsoleMailer ////////////////////////////////////////////////////////////////////////////////////////
+
+pub struct ConsoleMailer {
+    from: String,
+}
+
+impl ConsoleMailer {
+    pub fn new(from: String) -> Self {
+        Self { from }
+    }
+}
+
+#[async_trait::async_trait]
+impl Mailer for ConsoleMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        println!("{}", email.render(&self.from));
+        Ok(())
+    }
+}
+
+
+/////////// FileMailer ///////////////////////////////////////////////////////////////////////////////////////////
+
+pub struct FileMailer {
+    from: String,
+    path: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(from: String, path: PathBuf) -> Self {
+        Self { from, path }
+    }
+}
+
+#[async_trait::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| DeliveryError(format!("Cannot open {}: {}", self.path.display(), e)))?;
+        writeln!(file, "{}", email.render(&self.from))
+            .map_err(|e| DeliveryError(format!("Cannot write {}: {}", self.path.display(), e)))
+    }
+}
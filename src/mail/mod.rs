@@ -0,0 +1,5 @@
+mod errors;
+
+pub mod mailer;
+
+pub use self::errors::{Error, Result};
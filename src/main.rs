@@ -1,3 +1,6 @@
+mod audit;
+mod export;
+mod mail;
 mod products;
 mod user;
 mod web;
@@ -8,21 +11,39 @@ use actix_web::{web::Data, App, HttpServer};
 use products::products_repository::MemoryProductsRepository;
 use web::product_routes::ProductRoutes;
 
-use crate::{web::user_routes::UserRoutes};
+use crate::audit::audit_sink::AuditExport;
+use crate::export::personal_data::PersonalDataExporter;
+use crate::user::{config::UserConfig, user_service::UserService};
+use crate::web::{authorization::Authorization, cookies::CookieConfig, user_routes::UserRoutes};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let audit = audit::audit_sink::from_env().map_err(std::io::Error::other)?;
     let products_repository = Arc::new(MemoryProductsRepository::new());
-    let products_api = Data::new(ProductRoutes::new(products_repository));
+    let products_api = Data::new(ProductRoutes::new(products_repository.clone(), audit.clone()));
 
-    let users_api = Data::new(UserRoutes::new(
-        Arc::new(user::add_users())
-    ));
+    let mut user_config = UserConfig::from_env().map_err(std::io::Error::other)?;
+    let admin = user_config.admin.take();
+    let user_service = Arc::new(user::add_users(user_config));
+    if let Some(admin) = admin {
+        user_service
+            .ensure_admin(admin)
+            .await
+            .map_err(|err| std::io::Error::other(format!("{:?}", err)))?;
+    }
+    UserService::spawn_session_sweeper(user_service.clone());
+    let cookies = CookieConfig::from_env().map_err(std::io::Error::other)?;
+    let authorization = Authorization::new(user_service.clone(), cookies.clone());
+    let exporter = PersonalDataExporter::new()
+        .with_source(user_service.clone())
+        .with_source(Arc::new(AuditExport::new(audit.clone())))
+        .with_source(products_repository);
+    let users_api = Data::new(UserRoutes::new(user_service, cookies, audit, exporter));
 
     HttpServer::new(move || {
         App::new()
-            .service(ProductRoutes::scope(products_api.clone()))
-            .service(UserRoutes::scope(users_api.clone()))
+            .service(ProductRoutes::scope(products_api.clone(), authorization.clone()))
+            .service(UserRoutes::scope(users_api.clone(), authorization.clone()))
     })
     .bind("127.0.0.1:8081")?
     .run()
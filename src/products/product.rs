@@ -6,14 +6,17 @@ pub struct Product {
     pub id: Uuid,
     pub name: String,
     pub price: f64,
+    /// Id of the user who created the product.
+    pub owner_id: u32,
 }
 
 impl Product  {
-    pub fn new(name: String, price: f64) -> Self {
+    pub fn new(name: String, price: f64, owner_id: u32) -> Self {
         Self {
             id: Uuid::new_v4(),
             name,
             price,
+            owner_id,
         }
     }
 }
@@ -3,6 +3,9 @@ use async_trait::async_trait;
 
 use uuid::Uuid;
 
+use crate::export::{self, personal_data::PersonalDataSource};
+use crate::user::user_service::UserInfo;
+
 use crate::products::Error::{NotFound, InvalidInput, AlreadyExists};
 use crate::products::Result;
 
@@ -10,8 +13,9 @@ use crate::products::product::Product;
 
 #[async_trait]
 pub trait ProductRepository: Send + Sync {
-    async fn add_product(&self, name: String, price: f64) -> Result<Product>;
+    async fn add_product(&self, name: String, price: f64, owner_id: u32) -> Result<Product>;
     async fn get_products(&self) -> Result<Vec<Product>>;
+    async fn get_products_by_owner(&self, owner_id: u32) -> Result<Vec<Product>>;
     async fn get_product_by_id(&self, id: Uuid) -> Result<Product>;
     async fn update_product(&self, id: Uuid, name: String, price: f64) -> Result<Product>;
     async fn delete_product(&self, id: Uuid) -> Result<()>;
@@ -32,11 +36,11 @@ impl MemoryProductsRepository {
 
 #[async_trait::async_trait]
 impl ProductRepository for  MemoryProductsRepository {
-    async fn add_product(&self, name: String, price: f64) -> Result<Product> {
+    async fn add_product(&self, name: String, price: f64, owner_id: u32) -> Result<Product> {
         if name.is_empty() || price <= 0.0 {
             return Err(InvalidInput("Name cannot be empty and price must be greater than zero".to_string()));
         }
-        let product = Product::new(name, price);
+        let product = Product::new(name, price, owner_id);
         let mut products = self.products.lock().unwrap();
 
         if products.iter().any(|p| p.name == product.name) {
@@ -53,6 +57,11 @@ impl ProductRepository for  MemoryProductsRepository {
         Ok(products.clone())
     }
 
+    async fn get_products_by_owner(&self, owner_id: u32) -> Result<Vec<Product>> {
+        let products = self.products.lock().unwrap();
+        Ok(products.iter().filter(|p| p.owner_id == owner_id).cloned().collect())
+    }
+
     async fn get_product_by_id(&self, id: Uuid) -> Result<Product> {
         let products = self.products.lock().unwrap();
         let product = products.iter().find(|&p| p.id == id).cloned();
@@ -90,3 +99,19 @@ impl ProductRepository for  MemoryProductsRepository {
         }
     }
 }
+
+/// Products created by the user.
+#[async_trait]
+impl PersonalDataSource for MemoryProductsRepository {
+    fn section(&self) -> &'static str {
+        "products"
+    }
+
+    async fn export(&self, user: &UserInfo) -> export::Result<serde_json::Value> {
+        let products = self
+            .get_products_by_owner(user.id)
+            .await
+            .map_err(|e| export::Error::SourceError(format!("{:?}", e)))?;
+        serde_json::to_value(products).map_err(|e| export::Error::SourceError(e.to_string()))
+    }
+}
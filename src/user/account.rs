@@ -0,0 +1,29 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the placeholder usernames of erased accounts, which users cannot pick.
+pub const ERASED_USERNAME_PREFIX: &str = "deleted-user-";
+
+/// Lifecycle of an account. Only active accounts can sign in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AccountStatus {
+    Active,
+    /// Suspended by the user or an admin; can be reactivated by an admin.
+    Deactivated,
+    /// Deleted, but restorable by an admin until the grace period ends and the account is erased.
+    PendingDeletion { erase_after: DateTime<Utc> },
+    /// Personal data was anonymized. The record is kept so that references to its id stay valid.
+    Erased,
+}
+
+/// Current password, asked again before destructive self-service actions.
+#[derive(Deserialize, Serialize)]
+pub struct ConfirmPasswordRequest {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct ScheduledDeletion {
+    pub erase_after: DateTime<Utc>,
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::user::{Error::NotFound, Result, permission::Permission};
+
+/// Prefix of every key handed out, followed by the key id and the secret: `ak_<id>_<secret>`.
+pub const API_KEY_PREFIX: &str = "ak_";
+
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: u32,
+    pub name: String,
+    /// Hash of the secret part of the key, produced by `hash_password`.
+    pub hash: String,
+    pub scopes: Vec<Permission>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Permission>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            scopes: key.scopes,
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Scopes requested for the key; defaults to everything the user's roles allow.
+    #[serde(default)]
+    pub scope: Option<Vec<Permission>>,
+}
+
+/// Returned once on creation: the plain key is not stored and cannot be retrieved again.
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+}
+
+#[async_trait::async_trait]
+pub trait ApiKeyRepository: Send + Sync {
+    async fn add_api_key(&self, key: ApiKey) -> Result<ApiKey>;
+    async fn get_api_key(&self, id: Uuid) -> Result<ApiKey>;
+    async fn get_api_keys_by_user(&self, user_id: u32) -> Result<Vec<ApiKey>>;
+    async fn delete_api_key(&self, id: Uuid) -> Result<()>;
+}
+
+
+/////////// MemoryApiKeyRepository ///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone)]
+pub struct MemoryApiKeyRepository {
+    keys: Arc<Mutex<Vec<ApiKey>>>,
+}
+
+impl MemoryApiKeyRepository {
+    pub fn new() -> Self {
+        Self {
+            keys: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ApiKeyRepository for MemoryApiKeyRepository {
+    async fn add_api_key(&self, key: ApiKey) -> Result<ApiKey> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.push(key.clone());
+        Ok(key)
+    }
+
+    async fn get_api_key(&self, id: Uuid) -> Result<ApiKey> {
+        let keys = self.keys.lock().unwrap();
+        keys.iter()
+            .find(|k| k.id == id)
+            .cloned()
+            .ok_or_else(|| NotFound(format!("API key with id {} not found", id)))
+    }
+
+    async fn get_api_keys_by_user(&self, user_id: u32) -> Result<Vec<ApiKey>> {
+        let keys = self.keys.lock().unwrap();
+        Ok(keys.iter().filter(|k| k.user_id == user_id).cloned().collect())
+    }
+
+    async fn delete_api_key(&self, id: Uuid) -> Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.iter().position(|k| k.id == id) {
+            Some(pos) => {
+                keys.remove(pos);
+                Ok(())
+            }
+            None => Err(NotFound(format!("API key with id {} not found", id))),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+
+use actix_web::web;
+use sha1::{Digest, Sha1};
+
+use crate::utils::env::var_or;
+
+/// Length of the SHA-1 prefix naming each file of the corpus.
+const PREFIX_LENGTH: usize = 5;
+
+/// Local copy of a breached password corpus in the Have I Been Pwned "range" layout: one file
+/// per 5 character SHA-1 prefix (`21BD1` or `21BD1.txt`), each line holding the remaining 35
+/// characters of a hash and how often it was seen, as `SUFFIX:COUNT`. Only the file matching
+/// the candidate's prefix is read, so no request ever leaves the machine.
+#[derive(Debug, Clone)]
+pub struct BreachCorpus {
+    dir: PathBuf,
+    /// Passwords seen at least this many times are rejected.
+    threshold: u64,
+}
+
+impl BreachCorpus {
+    pub fn new(dir: PathBuf, threshold: u64) -> Result<Self, String> {
+        if !dir.is_dir() {
+            return Err(format!("Breached password corpus {} is not a directory", dir.display()));
+        }
+        Ok(Self {
+            dir,
+            threshold: threshold.max(1),
+        })
+    }
+
+    /// Reads `BREACHED_PASSWORDS_DIR` and `BREACHED_PASSWORDS_THRESHOLD`; the check is disabled
+    /// when no directory is configured.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match std::env::var("BREACHED_PASSWORDS_DIR") {
+            Ok(dir) => Self::new(
+                PathBuf::from(dir),
+                var_or("BREACHED_PASSWORDS_THRESHOLD", 1),
+            )
+            .map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Number of times `password` appears in the corpus, if it reaches the threshold. The range
+    /// file is read on the blocking thread pool; a lookup that cannot run counts as no match.
+    pub async fn breach_count(&self, password: &str) -> Option<u64> {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let corpus = self.clone();
+        web::block(move || corpus.lookup(&hash)).await.ok().flatten()
+    }
+
+    fn lookup(&self, hash: &str) -> Option<u64> {
+        let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+
+        let file = [format!("{}.txt", prefix), prefix.to_string()]
+            .iter()
+            .find_map(|name| File::open(self.dir.join(name)).ok())?;
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .find_map(|line| {
+                let (candidate, count) = line.trim().split_once(':')?;
+                if !candidate.eq_ignore_ascii_case(suffix) {
+                    return None;
+                }
+                count.trim().parse::<u64>().ok()
+            })
+            .filter(|count| *count >= self.threshold)
+    }
+}
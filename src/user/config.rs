@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+
+use crate::{
+    mail::mailer::{self, Mailer},
+    user::{
+        jwt::KeyRing, lockout::LockoutConfig, oidc::OidcProviderConfig, password_policy::PasswordPolicy, session::SessionConfig,
+        user_service::CreateUserRequest, verification::VerificationConfig,
+    },
+    utils::{env::var_or, password_handler::HashingConfig},
+};
+
+/// How access tokens returned by `UserService::login` are produced and validated.
+#[derive(Clone)]
+pub enum TokenMode {
+    /// Random UUIDs looked up in the in-process session map.
+    Opaque,
+    /// Self-contained signed JWTs that any instance sharing the key ring can validate.
+    Jwt(Arc<KeyRing>),
+}
+
+#[derive(Clone)]
+pub struct UserConfig {
+    pub session: SessionConfig,
+    pub lockout: LockoutConfig,
+    pub token_mode: TokenMode,
+    pub verification: VerificationConfig,
+    pub hashing: HashingConfig,
+    pub password_policy: PasswordPolicy,
+    /// External identity providers users can log in with.
+    pub oidc_providers: Vec<OidcProviderConfig>,
+    /// Issuer shown by authenticator apps for TOTP enrollments.
+    pub totp_issuer: String,
+    /// Delivers verification links and password reset tokens.
+    pub mailer: Arc<dyn Mailer>,
+    /// Lifetime of a password reset token.
+    pub password_reset_ttl: Duration,
+    /// Time a deleted account can still be restored before it is erased.
+    pub deletion_grace_period: Duration,
+    /// Time a former username stays reserved for the account that gave it up.
+    pub username_reservation: Duration,
+    /// Account created (or promoted) as admin at startup, from `ADMIN_USERNAME`, `ADMIN_EMAIL`
+    /// and `ADMIN_PASSWORD`.
+    pub admin: Option<CreateUserRequest>,
+}
+
+impl UserConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let token_mode = match var_or("TOKEN_MODE", "opaque".to_string()).as_str() {
+            "opaque" => TokenMode::Opaque,
+            "jwt" => TokenMode::Jwt(Arc::new(KeyRing::from_env()?)),
+            other => return Err(format!("Unknown TOKEN_MODE '{}'", other)),
+        };
+
+        let admin = match (
+            std::env::var("ADMIN_USERNAME"),
+            std::env::var("ADMIN_EMAIL"),
+            std::env::var("ADMIN_PASSWORD"),
+        ) {
+            (Ok(username), Ok(email), Ok(password)) => Some(CreateUserRequest {
+                username,
+                email,
+                password,
+            }),
+            _ => None,
+        };
+
+        let verification = VerificationConfig::from_env();
+        let oidc_providers = OidcProviderConfig::from_env(&verification.public_url)?;
+
+        Ok(Self {
+            session: SessionConfig::from_env(),
+            lockout: LockoutConfig::from_env(),
+            token_mode,
+            verification,
+            hashing: HashingConfig::from_env()?,
+            password_policy: PasswordPolicy::from_env()?,
+            oidc_providers,
+            totp_issuer: var_or("TOTP_ISSUER", "actixserver".to_string()),
+            mailer: mailer::from_env()?,
+            password_reset_ttl: Duration::seconds(var_or("PASSWORD_RESET_TTL_SECS", 3600)),
+            deletion_grace_period: Duration::seconds(var_or("ACCOUNT_DELETION_GRACE_SECS", 30 * 24 * 3600)),
+            username_reservation: Duration::seconds(var_or("USERNAME_RESERVATION_SECS", 90 * 24 * 3600)),
+            admin,
+        })
+    }
+}
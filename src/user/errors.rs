@@ -1,7 +1,10 @@
 use serde::Serialize;
 
+use crate::user::password_policy::PasswordViolation;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Serialize)]
 pub enum Error {
     NotFound(String),
@@ -9,4 +12,14 @@ pub enum Error {
     InvalidInput(String),
     HashingError(String),
     InvalidCredentials(String),
+    TokenError(String),
+    /// Too many failed attempts; holds the number of seconds before the next try is allowed.
+    TooManyAttempts(i64),
+    EmailNotVerified(String),
+    /// The password breaks the configured `PasswordPolicy`.
+    WeakPassword(Vec<PasswordViolation>),
+    /// An external identity provider could not be reached or answered unexpectedly.
+    ProviderError(String),
+    /// The account is deactivated, pending deletion or erased.
+    AccountDisabled(String),
 }
\ No newline at end of file
@@ -0,0 +1,255 @@
+use std::fs;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::{
+    SigningKey,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    user::{
+        Error, Result,
+        permission::Permission,
+        role::Role,
+        user_service::{AuthContext, UserInfo},
+    },
+    utils::env::var_or,
+};
+
+/// Tokens can be decoded by anyone holding them, so they carry no personal data beyond the
+/// username.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub username: String,
+    pub roles: Vec<Role>,
+    pub scope: Vec<Permission>,
+    pub iss: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+    /// Token family of the login this token was issued for.
+    pub sid: String,
+}
+
+impl Claims {
+    pub fn auth_context(&self) -> Result<AuthContext> {
+        let id = self
+            .sub
+            .parse()
+            .map_err(|_| Error::InvalidCredentials("Invalid token subject".to_string()))?;
+        Ok(AuthContext {
+            user: UserInfo {
+                id,
+                username: self.username.clone(),
+                // Not in the token; code needing the address loads the account.
+                email: String::new(),
+                roles: self.roles.clone(),
+                last_login: DateTime::from_timestamp(self.iat, 0),
+            },
+            scopes: self.scope.clone(),
+            family: self.family().ok(),
+        })
+    }
+
+    pub fn family(&self) -> Result<Uuid> {
+        Uuid::parse_str(&self.sid)
+            .map_err(|_| Error::InvalidCredentials("Invalid token session".to_string()))
+    }
+}
+
+/// Public part of an asymmetric signing key, as published on `/.well-known/jwks.json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwk {
+    pub kty: &'static str,
+    pub crv: &'static str,
+    pub alg: &'static str,
+    #[serde(rename = "use")]
+    pub usage: &'static str,
+    pub kid: String,
+    pub x: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+pub struct JwtKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    public: Option<Jwk>,
+}
+
+impl JwtKey {
+    pub fn hs256(kid: String, secret: &[u8]) -> Self {
+        Self {
+            kid,
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            public: None,
+        }
+    }
+
+    pub fn ed25519(kid: String, signing_key: &SigningKey) -> std::result::Result<Self, String> {
+        let der = signing_key
+            .to_pkcs8_der()
+            .map_err(|e| format!("Failed to encode Ed25519 key '{}': {}", kid, e))?;
+        let x = URL_SAFE_NO_PAD.encode(signing_key.verifying_key().as_bytes());
+        let decoding = DecodingKey::from_ed_components(&x)
+            .map_err(|e| format!("Invalid Ed25519 public key '{}': {}", kid, e))?;
+
+        Ok(Self {
+            kid: kid.clone(),
+            algorithm: Algorithm::EdDSA,
+            encoding: EncodingKey::from_ed_der(der.as_bytes()),
+            decoding,
+            public: Some(Jwk {
+                kty: "OKP",
+                crv: "Ed25519",
+                alg: "EdDSA",
+                usage: "sig",
+                kid,
+                x,
+            }),
+        })
+    }
+}
+
+/// Set of signing keys identified by `kid`. New tokens are always signed with the active key,
+/// while any key still present in the ring is accepted for verification, which allows keys to be
+/// rotated without invalidating tokens issued shortly before the rotation.
+pub struct KeyRing {
+    keys: Vec<JwtKey>,
+    active_kid: String,
+    issuer: String,
+    ttl: Duration,
+}
+
+impl KeyRing {
+    pub fn new(
+        keys: Vec<JwtKey>,
+        active_kid: String,
+        issuer: String,
+        ttl: Duration,
+    ) -> std::result::Result<Self, String> {
+        if !keys.iter().any(|k| k.kid == active_kid) {
+            return Err(format!("Active key '{}' is not part of the key ring", active_kid));
+        }
+        Ok(Self {
+            keys,
+            active_kid,
+            issuer,
+            ttl,
+        })
+    }
+
+    /// Builds the key ring from `JWT_KEYS`, a comma separated list of `kid:ALG:material` entries
+    /// where `ALG` is `HS256` (material is the shared secret) or `EdDSA` (material is the path of
+    /// a PKCS#8 PEM private key). `JWT_ACTIVE_KID` selects the signing key and defaults to the
+    /// last entry. `JWT_KEYS` is required: a key only known to this process would log everyone
+    /// out on restart and break as soon as a second instance runs.
+    pub fn from_env() -> std::result::Result<Self, String> {
+        let issuer = var_or("JWT_ISSUER", "actixserver".to_string());
+        let ttl = Duration::seconds(var_or("JWT_TTL_SECS", 900));
+
+        let spec = std::env::var("JWT_KEYS")
+            .map_err(|_| "JWT_KEYS must be set when TOKEN_MODE is jwt".to_string())?;
+        let keys = parse_keys(&spec)?;
+        let active_kid = match std::env::var("JWT_ACTIVE_KID") {
+            Ok(kid) => kid,
+            Err(_) => keys
+                .last()
+                .map(|k| k.kid.clone())
+                .ok_or_else(|| "JWT_KEYS does not contain any key".to_string())?,
+        };
+
+        Self::new(keys, active_kid, issuer, ttl)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Signs an access token, expiring after the configured lifetime or at `deadline` if earlier.
+    pub fn issue(&self, user: &UserInfo, scopes: &[Permission], family: Uuid, deadline: DateTime<Utc>) -> Result<String> {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: user.id.to_string(),
+            username: user.username.clone(),
+            roles: user.roles.clone(),
+            scope: scopes.to_vec(),
+            iss: self.issuer.clone(),
+            iat: now.timestamp(),
+            exp: (now + self.ttl).min(deadline).timestamp(),
+            jti: Uuid::new_v4().to_string(),
+            sid: family.to_string(),
+        };
+
+        let key = self.key(&self.active_kid)?;
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+
+        jsonwebtoken::encode(&header, &claims, &key.encoding)
+            .map_err(|e| Error::TokenError(format!("Failed to sign token: {}", e)))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| Error::InvalidCredentials("Malformed token".to_string()))?;
+        let kid = header
+            .kid
+            .ok_or_else(|| Error::InvalidCredentials("Token has no key id".to_string()))?;
+        let key = self.key(&kid)?;
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.leeway = 0;
+
+        jsonwebtoken::decode::<Claims>(token, &key.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| Error::InvalidCredentials("Invalid or expired token".to_string()))
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.iter().filter_map(|k| k.public.clone()).collect(),
+        }
+    }
+
+    fn key(&self, kid: &str) -> Result<&JwtKey> {
+        self.keys
+            .iter()
+            .find(|k| k.kid == kid)
+            .ok_or_else(|| Error::InvalidCredentials(format!("Unknown key id '{}'", kid)))
+    }
+}
+
+fn parse_keys(spec: &str) -> std::result::Result<Vec<JwtKey>, String> {
+    spec.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let mut parts = entry.trim().splitn(3, ':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(kid), Some("HS256"), Some(secret)) => {
+                    Ok(JwtKey::hs256(kid.to_string(), secret.as_bytes()))
+                }
+                (Some(kid), Some("EdDSA"), Some(path)) => {
+                    let pem = fs::read_to_string(path)
+                        .map_err(|e| format!("Cannot read key '{}' from {}: {}", kid, path, e))?;
+                    let signing_key = SigningKey::from_pkcs8_pem(&pem)
+                        .map_err(|e| format!("Invalid Ed25519 key '{}': {}", kid, e))?;
+                    JwtKey::ed25519(kid.to_string(), &signing_key)
+                }
+                _ => Err(format!("Invalid JWT_KEYS entry '{}'", entry)),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{
+    user::{Error, Result},
+    utils::env::var_or,
+};
+
+#[derive(Debug, Clone)]
+pub struct LockoutConfig {
+    /// Consecutive failures for one username before the account is locked.
+    pub max_failures: u32,
+    /// Failures from one IP address, across usernames, before that address is locked out.
+    pub ip_max_failures: u32,
+    /// Delay imposed after the first failure, doubled after each further failure.
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// How long a lockout lasts; failures older than this are forgotten.
+    pub lockout_duration: Duration,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            ip_max_failures: 50,
+            backoff_base: Duration::seconds(1),
+            backoff_max: Duration::seconds(60),
+            lockout_duration: Duration::minutes(15),
+        }
+    }
+}
+
+impl LockoutConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_failures: var_or("LOGIN_MAX_FAILURES", default.max_failures),
+            ip_max_failures: var_or("LOGIN_IP_MAX_FAILURES", default.ip_max_failures),
+            backoff_base: Duration::seconds(var_or(
+                "LOGIN_BACKOFF_BASE_SECS",
+                default.backoff_base.num_seconds(),
+            )),
+            backoff_max: Duration::seconds(var_or(
+                "LOGIN_BACKOFF_MAX_SECS",
+                default.backoff_max.num_seconds(),
+            )),
+            lockout_duration: Duration::seconds(var_or(
+                "LOGIN_LOCKOUT_SECS",
+                default.lockout_duration.num_seconds(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Attempts {
+    failures: u32,
+    /// Attempts let through whose outcome is not known yet.
+    pending: u32,
+    last_failure: Option<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl Attempts {
+    /// Earliest time a new attempt is allowed, if it is currently held back.
+    fn retry_at(&self, config: &LockoutConfig) -> Option<DateTime<Utc>> {
+        if let Some(locked_until) = self.locked_until {
+            return Some(locked_until);
+        }
+        let last_failure = self.last_failure?;
+        let exponent = self.failures.saturating_sub(1).min(16);
+        let delay = (config.backoff_base * 2i32.pow(exponent)).min(config.backoff_max);
+        Some(last_failure + delay)
+    }
+
+    fn is_stale(&self, now: DateTime<Utc>, config: &LockoutConfig) -> bool {
+        let lock_expired = self.locked_until.is_none_or(|until| until <= now);
+        let failures_expired = self
+            .last_failure
+            .is_none_or(|last| now - last >= config.lockout_duration);
+        self.pending == 0 && lock_expired && failures_expired
+    }
+
+    fn record_failure(&mut self, now: DateTime<Utc>, max_failures: u32, config: &LockoutConfig) {
+        if self.locked_until.is_some_and(|until| until <= now) || self.is_stale(now, config) {
+            *self = Attempts {
+                pending: self.pending,
+                ..Attempts::default()
+            };
+        }
+        self.failures += 1;
+        self.last_failure = Some(now);
+        if self.failures >= max_failures {
+            self.locked_until = Some(now + config.lockout_duration);
+        }
+    }
+}
+
+/// Failed login counters per username and per client IP, applying exponential backoff between
+/// attempts and a temporary lockout once too many attempts failed.
+pub struct LoginThrottle {
+    config: LockoutConfig,
+    usernames: Mutex<HashMap<String, Attempts>>,
+    ips: Mutex<HashMap<String, Attempts>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: LockoutConfig) -> Self {
+        Self {
+            config,
+            usernames: Mutex::new(HashMap::new()),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lets an attempt through, or refuses it while the username or IP is held back. The check
+    /// and the reservation happen under the same locks: an attempt counts against the limits
+    /// until it is settled, so concurrent guesses cannot all pass before the first one fails.
+    /// Only one attempt per username is verified at a time.
+    pub fn begin(&self, username: &str, ip: Option<&str>) -> Result<PendingAttempt<'_>> {
+        let now = Utc::now();
+        let username = normalize(username);
+        let mut usernames = self.usernames.lock().unwrap();
+        let mut ips = self.ips.lock().unwrap();
+        let user_attempts = usernames.get(&username);
+        let ip_attempts = ip.and_then(|ip| ips.get(ip));
+
+        let username_retry = user_attempts.and_then(|attempts| match attempts.pending {
+            0 => attempts.retry_at(&self.config),
+            _ => Some(now + Duration::seconds(1)),
+        });
+        // Many users can share an address, so IPs are only held back once fully locked out,
+        // or when enough attempts are in flight to reach the lockout.
+        let ip_retry = ip_attempts.and_then(|attempts| {
+            if attempts.failures + attempts.pending >= self.config.ip_max_failures {
+                Some(attempts.locked_until.unwrap_or(now + Duration::seconds(1)))
+            } else {
+                attempts.locked_until
+            }
+        });
+        if let Some(retry_at) = username_retry.into_iter().chain(ip_retry).max()
+            && retry_at > now
+        {
+            return Err(Error::TooManyAttempts((retry_at - now).num_seconds().max(1)));
+        }
+
+        usernames.entry(username.clone()).or_default().pending += 1;
+        if let Some(ip) = ip {
+            ips.entry(ip.to_string()).or_default().pending += 1;
+        }
+        Ok(PendingAttempt {
+            throttle: self,
+            username,
+            ip: ip.map(str::to_string),
+        })
+    }
+
+    fn record_failure(&self, username: &str, ip: Option<&str>) {
+        let now = Utc::now();
+        self.usernames
+            .lock()
+            .unwrap()
+            .entry(normalize(username))
+            .or_default()
+            .record_failure(now, self.config.max_failures, &self.config);
+        if let Some(ip) = ip {
+            self.ips
+                .lock()
+                .unwrap()
+                .entry(ip.to_string())
+                .or_default()
+                .record_failure(now, self.config.ip_max_failures, &self.config);
+        }
+    }
+
+    /// A successful login clears the username counter. The IP counter is left alone so that an
+    /// attacker cannot reset it by logging into an account of their own between guesses.
+    fn record_success(&self, username: &str) {
+        self.usernames.lock().unwrap().remove(&normalize(username));
+    }
+
+    fn release(&self, username: &str, ip: Option<&str>) {
+        if let Some(attempts) = self.usernames.lock().unwrap().get_mut(username) {
+            attempts.pending = attempts.pending.saturating_sub(1);
+        }
+        if let Some(ip) = ip
+            && let Some(attempts) = self.ips.lock().unwrap().get_mut(ip)
+        {
+            attempts.pending = attempts.pending.saturating_sub(1);
+        }
+    }
+
+    pub fn unlock(&self, username: &str) -> bool {
+        self.usernames.lock().unwrap().remove(&normalize(username)).is_some()
+    }
+
+    pub fn purge(&self) {
+        let now = Utc::now();
+        self.usernames
+            .lock()
+            .unwrap()
+            .retain(|_, attempts| !attempts.is_stale(now, &self.config));
+        self.ips
+            .lock()
+            .unwrap()
+            .retain(|_, attempts| !attempts.is_stale(now, &self.config));
+    }
+}
+
+/// An attempt let through by `LoginThrottle::begin`. It is settled with `failed` or
+/// `succeeded`; dropping it otherwise, e.g. on an internal error, counts as neither.
+pub struct PendingAttempt<'a> {
+    throttle: &'a LoginThrottle,
+    username: String,
+    ip: Option<String>,
+}
+
+impl PendingAttempt<'_> {
+    pub fn failed(self) {
+        self.throttle.record_failure(&self.username, self.ip.as_deref());
+    }
+
+    pub fn succeeded(self) {
+        self.throttle.record_success(&self.username);
+    }
+}
+
+impl Drop for PendingAttempt<'_> {
+    fn drop(&mut self) {
+        self.throttle.release(&self.username, self.ip.as_deref());
+    }
+}
+
+fn normalize(username: &str) -> String {
+    username.trim().to_lowercase()
+}
@@ -0,0 +1,76 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::user::{Result, session::ClientInfo};
+
+/// Number of successful, and separately of failed, attempts kept per user; older ones are
+/// dropped first. Anyone can fail logins on an account, so failures must not be able to push
+/// the successful ones out.
+pub const MAX_ATTEMPTS_PER_USER: usize = 100;
+
+/// A sign-in attempt on a known account, successful or not.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginAttempt {
+    #[serde(skip)]
+    pub user_id: u32,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+    /// `password`, `totp` or `oidc:<provider>`.
+    pub method: String,
+    pub success: bool,
+    pub failure_reason: Option<String>,
+}
+
+#[async_trait::async_trait]
+pub trait LoginHistoryRepository: Send + Sync {
+    async fn add_attempt(&self, attempt: LoginAttempt) -> Result<()>;
+    /// Attempts of a user, most recent first.
+    async fn get_attempts_by_user(&self, user_id: u32) -> Result<Vec<LoginAttempt>>;
+    async fn delete_attempts_by_user(&self, user_id: u32) -> Result<()>;
+}
+
+
+/////////// MemoryLoginHistoryRepository /////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone)]
+pub struct MemoryLoginHistoryRepository {
+    attempts: Arc<Mutex<Vec<LoginAttempt>>>,
+}
+
+impl MemoryLoginHistoryRepository {
+    pub fn new() -> Self {
+        Self {
+            attempts: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl LoginHistoryRepository for MemoryLoginHistoryRepository {
+    async fn add_attempt(&self, attempt: LoginAttempt) -> Result<()> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let (user_id, success) = (attempt.user_id, attempt.success);
+        attempts.push(attempt);
+        let same_kind = |a: &LoginAttempt| a.user_id == user_id && a.success == success;
+        if attempts.iter().filter(|a| same_kind(a)).count() > MAX_ATTEMPTS_PER_USER
+            && let Some(oldest) = attempts.iter().position(same_kind)
+        {
+            attempts.remove(oldest);
+        }
+        Ok(())
+    }
+
+    async fn get_attempts_by_user(&self, user_id: u32) -> Result<Vec<LoginAttempt>> {
+        let attempts = self.attempts.lock().unwrap();
+        Ok(attempts.iter().rev().filter(|a| a.user_id == user_id).cloned().collect())
+    }
+
+    async fn delete_attempts_by_user(&self, user_id: u32) -> Result<()> {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|a| a.user_id != user_id);
+        Ok(())
+    }
+}
@@ -1,15 +1,39 @@
+pub mod account;
+pub mod api_key;
+pub mod breach;
+pub mod config;
 mod errors;
+pub mod jwt;
+pub mod lockout;
+pub mod login_history;
+pub mod oidc;
+pub mod password_policy;
+pub mod password_reset;
+pub mod permission;
+pub mod profile;
+#[allow(clippy::module_inception)]
 mod user;
 mod user_repository;
+pub mod role;
+pub mod session;
+pub mod totp;
 pub mod user_service;
+pub mod verification;
 
 use std::sync::Arc;
 
 pub use errors::{Result, Error};
 
-use crate::user::user_service::UserService;
+use crate::user::{
+    api_key::MemoryApiKeyRepository, config::UserConfig,
+    login_history::MemoryLoginHistoryRepository, user_service::UserService,
+};
+use crate::utils::password_handler;
 
-pub fn add_users() -> UserService {
+pub fn add_users(config: UserConfig) -> UserService {
+    password_handler::configure(config.hashing.clone());
     let user_repository = Arc::new(user_repository::MemoryUserRepository::new());
-    UserService::new(user_repository)
+    let api_key_repository = Arc::new(MemoryApiKeyRepository::new());
+    let login_history_repository = Arc::new(MemoryLoginHistoryRepository::new());
+    UserService::new(user_repository, api_key_repository, login_history_repository, config)
 }
@@ -0,0 +1,320 @@
+use std::{sync::Mutex, time::Duration as StdDuration};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation,
+    jwk::{Jwk, JwkSet},
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    user::{Error, Result, role::Role},
+    utils::env::var_or,
+};
+
+/// Time left to come back from the identity provider once a login was started.
+pub const LOGIN_TTL_SECS: i64 = 600;
+
+/// Signature algorithms accepted on ID tokens; symmetric ones are never trusted.
+const ID_TOKEN_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug, Clone)]
+pub struct OidcProviderConfig {
+    /// Name used in the `/auth/{provider}` routes.
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    /// Sent to the token endpoint when set; PKCE alone is used for public clients.
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    /// Whether a user is created on the first login of an unknown identity.
+    pub auto_provision: bool,
+    /// Role of users created through this provider.
+    pub default_role: Role,
+}
+
+impl OidcProviderConfig {
+    /// Reads `OIDC_PROVIDERS`, a comma separated list of provider names, then for each name
+    /// `OIDC_<NAME>_ISSUER`, `OIDC_<NAME>_CLIENT_ID` and optionally `OIDC_<NAME>_CLIENT_SECRET`,
+    /// `OIDC_<NAME>_REDIRECT_URI`, `OIDC_<NAME>_SCOPES`, `OIDC_<NAME>_AUTO_PROVISION` and
+    /// `OIDC_<NAME>_DEFAULT_ROLE`.
+    pub fn from_env(public_url: &str) -> std::result::Result<Vec<Self>, String> {
+        let names = var_or("OIDC_PROVIDERS", String::new());
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let prefix = format!("OIDC_{}_", name.to_uppercase().replace('-', "_"));
+                let required = |key: &str| {
+                    std::env::var(format!("{}{}", prefix, key))
+                        .map_err(|_| format!("Missing {}{} for provider '{}'", prefix, key, name))
+                };
+                Ok(Self {
+                    name: name.to_string(),
+                    issuer: required("ISSUER")?.trim_end_matches('/').to_string(),
+                    client_id: required("CLIENT_ID")?,
+                    client_secret: required("CLIENT_SECRET").ok(),
+                    redirect_uri: required("REDIRECT_URI").unwrap_or_else(|_| {
+                        format!("{}/auth/{}/callback", public_url.trim_end_matches('/'), name)
+                    }),
+                    scopes: required("SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+                    auto_provision: var_or(&format!("{}AUTO_PROVISION", prefix), true),
+                    default_role: match required("DEFAULT_ROLE") {
+                        Ok(role) => role.parse()?,
+                        Err(_) => Role::Customer,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+/// Subset of the provider's `/.well-known/openid-configuration` document.
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+/// Claims of a validated ID token used to find or create the local user.
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    pub nonce: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// Email address the provider vouches for, the only one trusted to link existing accounts.
+    pub fn verified_email(&self) -> Option<&str> {
+        self.email.as_deref().filter(|_| self.email_verified)
+    }
+}
+
+/// Identity of a user at an external provider, linked to a local `User`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String,
+}
+
+/// Login started with `/auth/{provider}/start`, keyed by its `state` until the callback.
+#[derive(Debug, Clone)]
+pub struct OidcLogin {
+    pub provider: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct OidcStartQuery {
+    /// Forwarded to the provider to preselect an account.
+    pub login_hint: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+/// S256 PKCE challenge derived from a code verifier.
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Client for one configured provider. The discovery document and the signing keys are
+/// fetched on first use and cached; the keys are fetched again when a token names an
+/// unknown key id, which happens after the provider rotated its keys.
+pub struct OidcProvider {
+    config: OidcProviderConfig,
+    http: reqwest::Client,
+    metadata: Mutex<Option<ProviderMetadata>>,
+    jwks: Mutex<Option<JwkSet>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcProviderConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::builder()
+                .timeout(StdDuration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+            metadata: Mutex::new(None),
+            jwks: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &OidcProviderConfig {
+        &self.config
+    }
+
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+        login_hint: Option<&str>,
+    ) -> Result<String> {
+        let metadata = self.metadata().await?;
+        let mut url = Url::parse(&metadata.authorization_endpoint)
+            .map_err(|e| Error::ProviderError(format!("Invalid authorization endpoint: {}", e)))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        if let Some(login_hint) = login_hint {
+            url.query_pairs_mut().append_pair("login_hint", login_hint);
+        }
+        Ok(url.into())
+    }
+
+    /// Redeems an authorization code and returns the raw ID token.
+    pub async fn exchange_code(&self, code: &str, code_verifier: &str) -> Result<String> {
+        let metadata = self.metadata().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| Error::ProviderError(format!("Token request failed: {}", e)))?;
+        if response.status().is_client_error() {
+            return Err(Error::InvalidCredentials(format!(
+                "Authorization code was rejected ({})",
+                response.status()
+            )));
+        }
+        let response = response
+            .error_for_status()
+            .map_err(|e| Error::ProviderError(format!("Token request failed: {}", e)))?
+            .json::<TokenEndpointResponse>()
+            .await
+            .map_err(|e| Error::ProviderError(format!("Invalid token response: {}", e)))?;
+        Ok(response.id_token)
+    }
+
+    /// Checks the signature against the provider's JWKS, then issuer, audience, expiry and the
+    /// nonce bound to the login.
+    pub async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<IdTokenClaims> {
+        let invalid = |reason: &str| Error::InvalidCredentials(format!("Invalid ID token: {}", reason));
+        let header = jsonwebtoken::decode_header(id_token).map_err(|_| invalid("malformed"))?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(invalid("unsupported algorithm"));
+        }
+
+        let jwk = match self.find_key(header.kid.as_deref(), false).await? {
+            Some(jwk) => jwk,
+            None => self
+                .find_key(header.kid.as_deref(), true)
+                .await?
+                .ok_or_else(|| invalid("unknown signing key"))?,
+        };
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid("unusable signing key"))?;
+
+        let metadata = self.metadata().await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| invalid(&e.to_string()))?
+            .claims;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid("nonce mismatch"));
+        }
+        Ok(claims)
+    }
+
+    async fn find_key(&self, kid: Option<&str>, refresh: bool) -> Result<Option<Jwk>> {
+        let cached = if refresh { None } else { self.jwks.lock().unwrap().clone() };
+        let jwks = match cached {
+            Some(jwks) => jwks,
+            None => {
+                let metadata = self.metadata().await?;
+                let jwks: JwkSet = self.get_json(&metadata.jwks_uri).await?;
+                *self.jwks.lock().unwrap() = Some(jwks.clone());
+                jwks
+            }
+        };
+
+        Ok(match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        })
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata> {
+        if let Some(metadata) = self.metadata.lock().unwrap().clone() {
+            return Ok(metadata);
+        }
+        let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+        let metadata: ProviderMetadata = self.get_json(&url).await?;
+        if metadata.issuer.trim_end_matches('/') != self.config.issuer {
+            return Err(Error::ProviderError(format!(
+                "Discovery document is for issuer '{}'",
+                metadata.issuer
+            )));
+        }
+        *self.metadata.lock().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::ProviderError(format!("Request to {} failed: {}", url, e)))?
+            .json()
+            .await
+            .map_err(|e| Error::ProviderError(format!("Invalid response from {}: {}", url, e)))
+    }
+}
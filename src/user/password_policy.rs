@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{user::breach::BreachCorpus, utils::env::var_or};
+
+/// Frequently used passwords, one per line, compared case-insensitively.
+static COMMON_PASSWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    include_str!("common_passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect()
+});
+
+/// Usernames and email local parts shorter than this are not looked for in passwords.
+const MIN_USER_INFO_LENGTH: usize = 3;
+
+/// Rules every new password must follow, for registration as well as password changes.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Rejects passwords containing the username or the local part of the email address.
+    pub reject_user_info: bool,
+    /// Rejects passwords from the bundled list of common passwords.
+    pub reject_common: bool,
+    /// Optional local corpus of breached passwords, see `BreachCorpus`.
+    pub breach_corpus: Option<BreachCorpus>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            reject_user_info: true,
+            reject_common: true,
+            breach_corpus: None,
+        }
+    }
+}
+
+/// One rule a password failed, with a stable `code` clients can act upon.
+#[derive(Debug, Clone, Serialize)]
+pub struct PasswordViolation {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl PasswordViolation {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Result<Self, String> {
+        let default = Self::default();
+        Ok(Self {
+            min_length: var_or("PASSWORD_MIN_LENGTH", default.min_length),
+            max_length: var_or("PASSWORD_MAX_LENGTH", default.max_length),
+            require_lowercase: var_or("PASSWORD_REQUIRE_LOWERCASE", default.require_lowercase),
+            require_uppercase: var_or("PASSWORD_REQUIRE_UPPERCASE", default.require_uppercase),
+            require_digit: var_or("PASSWORD_REQUIRE_DIGIT", default.require_digit),
+            require_symbol: var_or("PASSWORD_REQUIRE_SYMBOL", default.require_symbol),
+            reject_user_info: var_or("PASSWORD_REJECT_USER_INFO", default.reject_user_info),
+            reject_common: var_or("PASSWORD_REJECT_COMMON", default.reject_common),
+            breach_corpus: BreachCorpus::from_env()?,
+        })
+    }
+
+    /// Returns every rule `password` breaks, so clients can report them all at once.
+    pub async fn check(
+        &self,
+        password: &str,
+        username: &str,
+        email: &str,
+    ) -> Result<(), Vec<PasswordViolation>> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            violations.push(PasswordViolation::new(
+                "too_short",
+                format!("Password must be at least {} characters long", self.min_length),
+            ));
+        }
+        if length > self.max_length {
+            violations.push(PasswordViolation::new(
+                "too_long",
+                format!("Password must be at most {} characters long", self.max_length),
+            ));
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            violations.push(PasswordViolation::new(
+                "missing_lowercase",
+                "Password must contain a lowercase letter",
+            ));
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            violations.push(PasswordViolation::new(
+                "missing_uppercase",
+                "Password must contain an uppercase letter",
+            ));
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push(PasswordViolation::new(
+                "missing_digit",
+                "Password must contain a digit",
+            ));
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            violations.push(PasswordViolation::new(
+                "missing_symbol",
+                "Password must contain a symbol",
+            ));
+        }
+
+        let lowercase = password.to_lowercase();
+        if self.reject_user_info {
+            let local_part = email.split('@').next().unwrap_or_default();
+            let contains = |value: &str| {
+                value.chars().count() >= MIN_USER_INFO_LENGTH
+                    && lowercase.contains(&value.to_lowercase())
+            };
+            if contains(username) {
+                violations.push(PasswordViolation::new(
+                    "contains_username",
+                    "Password must not contain the username",
+                ));
+            }
+            if contains(local_part) {
+                violations.push(PasswordViolation::new(
+                    "contains_email",
+                    "Password must not contain the email address",
+                ));
+            }
+        }
+        if self.reject_common && COMMON_PASSWORDS.contains(lowercase.as_str()) {
+            violations.push(PasswordViolation::new(
+                "common_password",
+                "Password is too common",
+            ));
+        }
+
+        let breach_count = match &self.breach_corpus {
+            Some(corpus) => corpus.breach_count(password).await,
+            None => None,
+        };
+        if let Some(count) = breach_count {
+            violations.push(PasswordViolation::new(
+                "breached_password",
+                format!("Password appeared {} times in known data breaches", count),
+            ));
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Outstanding reset token, stored under the hash of the token sent by email.
+#[derive(Debug, Clone)]
+pub struct PasswordReset {
+    pub user_id: u32,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PasswordReset {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
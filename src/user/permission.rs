@@ -0,0 +1,42 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Permission scope carried by a token. A token never holds more scopes than the roles of its
+/// user allow; see `Role::permissions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    #[serde(rename = "products:read")]
+    ProductsRead,
+    #[serde(rename = "products:write")]
+    ProductsWrite,
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:admin")]
+    UsersAdmin,
+    /// Reading one's own account data (sessions, API keys, ...).
+    #[serde(rename = "account:read")]
+    AccountRead,
+    /// Managing one's own account (API keys, sessions, credentials, ...).
+    #[serde(rename = "account:write")]
+    AccountWrite,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ProductsRead => "products:read",
+            Permission::ProductsWrite => "products:write",
+            Permission::UsersRead => "users:read",
+            Permission::UsersAdmin => "users:admin",
+            Permission::AccountRead => "account:read",
+            Permission::AccountWrite => "account:write",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,57 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::user::{account::AccountStatus, role::Role, user_service::UserInfo};
+
+/// A username the user had before, kept so the handle cannot be taken over right away.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsernameChange {
+    pub username: String,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// Everything the owner of an account may see about it, returned by `GET /users/me`.
+#[derive(Serialize)]
+pub struct PrivateProfile {
+    pub id: u32,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub pending_email: Option<String>,
+    pub roles: Vec<Role>,
+    pub last_login: Option<DateTime<Utc>>,
+    pub totp_enabled: bool,
+    #[serde(flatten)]
+    pub status: AccountStatus,
+}
+
+/// What any signed in user may see about another account.
+#[derive(Serialize)]
+pub struct PublicProfile {
+    pub id: u32,
+    pub username: String,
+}
+
+impl From<UserInfo> for PublicProfile {
+    fn from(user: UserInfo) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+        }
+    }
+}
+
+/// Fields left out are not changed.
+#[derive(Deserialize, Serialize)]
+pub struct UpdateProfileRequest {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UpdatedProfile {
+    #[serde(flatten)]
+    pub user: UserInfo,
+    /// New address waiting for verification; the current one stays in use until then.
+    pub pending_email: Option<String>,
+}
@@ -0,0 +1,83 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::user::permission::Permission;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Editor,
+    Customer,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Editor => "editor",
+            Role::Customer => "customer",
+        }
+    }
+
+    /// Scopes a token of a user holding this role may be granted.
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Admin => &[
+                Permission::ProductsRead,
+                Permission::ProductsWrite,
+                Permission::UsersRead,
+                Permission::UsersAdmin,
+                Permission::AccountRead,
+                Permission::AccountWrite,
+            ],
+            Role::Editor => &[
+                Permission::ProductsRead,
+                Permission::ProductsWrite,
+                Permission::UsersRead,
+                Permission::AccountRead,
+                Permission::AccountWrite,
+            ],
+            Role::Customer => &[
+                Permission::ProductsRead,
+                Permission::UsersRead,
+                Permission::AccountRead,
+                Permission::AccountWrite,
+            ],
+        }
+    }
+
+    /// Roles are hierarchical: an admin can do everything an editor can, who can do everything a
+    /// customer can.
+    pub fn satisfies(&self, required: Role) -> bool {
+        self.rank() >= required.rank()
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Role::Admin => 2,
+            Role::Editor => 1,
+            Role::Customer => 0,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "admin" => Ok(Role::Admin),
+            "editor" => Ok(Role::Editor),
+            "customer" => Ok(Role::Customer),
+            other => Err(format!("Unknown role '{}'", other)),
+        }
+    }
+}
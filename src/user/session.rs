@@ -0,0 +1,165 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    user::{permission::Permission, user_service::UserInfo},
+    utils::env::var_or,
+};
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Maximum lifetime of a session, regardless of activity.
+    pub absolute_ttl: Duration,
+    /// A session unused for longer than this is considered expired.
+    pub idle_ttl: Duration,
+    /// Lifetime of a refresh token; each rotation issues a new one with a fresh lifetime.
+    pub refresh_ttl: Duration,
+    /// How often the background sweeper evicts expired sessions.
+    pub sweep_interval: StdDuration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            absolute_ttl: Duration::hours(12),
+            idle_ttl: Duration::minutes(30),
+            refresh_ttl: Duration::days(30),
+            sweep_interval: StdDuration::from_secs(60),
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            absolute_ttl: Duration::seconds(var_or(
+                "SESSION_ABSOLUTE_TTL_SECS",
+                default.absolute_ttl.num_seconds(),
+            )),
+            idle_ttl: Duration::seconds(var_or(
+                "SESSION_IDLE_TTL_SECS",
+                default.idle_ttl.num_seconds(),
+            )),
+            refresh_ttl: Duration::seconds(var_or(
+                "REFRESH_TOKEN_TTL_SECS",
+                default.refresh_ttl.num_seconds(),
+            )),
+            // A zero period would make the sweeper's interval panic.
+            sweep_interval: StdDuration::from_secs(
+                var_or("SESSION_SWEEP_INTERVAL_SECS", default.sweep_interval.as_secs()).max(1),
+            ),
+        }
+    }
+}
+
+/// Where a session was opened from, as reported by the client.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Token family shared with the refresh tokens issued for the same login.
+    pub family: Uuid,
+    pub user: UserInfo,
+    pub scopes: Vec<Permission>,
+    pub client: ClientInfo,
+    /// When this access token was issued; a refresh replaces the session with a new one.
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl Session {
+    pub fn new(
+        user: UserInfo,
+        scopes: Vec<Permission>,
+        family: Uuid,
+        client: ClientInfo,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            family,
+            user,
+            scopes,
+            client,
+            issued_at: now,
+            expires_at,
+            last_seen: now,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>, config: &SessionConfig) -> bool {
+        now >= self.expires_at || now - self.last_seen >= config.idle_ttl
+    }
+}
+
+/// End of the absolute lifetime of the sessions of a login made at `created_at`: refreshing
+/// renews the tokens but never past this point.
+pub fn session_deadline(created_at: DateTime<Utc>, config: &SessionConfig) -> DateTime<Utc> {
+    created_at + config.absolute_ttl
+}
+
+/// Long-lived token exchanged for a new access token. A rotated token is kept until it expires so
+/// that presenting it again can be detected as a replay.
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub family: Uuid,
+    pub user: UserInfo,
+    pub scopes: Vec<Permission>,
+    pub client: ClientInfo,
+    /// When the login that started this token family happened; carried over on rotation.
+    pub created_at: DateTime<Utc>,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub rotated: bool,
+}
+
+impl RefreshToken {
+    pub fn new(
+        user: UserInfo,
+        scopes: Vec<Permission>,
+        family: Uuid,
+        client: ClientInfo,
+        created_at: DateTime<Utc>,
+        config: &SessionConfig,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            family,
+            user,
+            scopes,
+            client,
+            created_at,
+            issued_at: now,
+            expires_at: (now + config.refresh_ttl).min(session_deadline(created_at, config)),
+            rotated: false,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires_at
+    }
+}
+
+/// A login as shown to its owner: one entry per token family.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+    pub created_at: DateTime<Utc>,
+    /// When the current access token of the session was issued.
+    pub issued_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
@@ -0,0 +1,166 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::{
+    user::{permission::Permission, user_service::UserInfo},
+    utils::{
+        password_handler::{constant_time_eq, hash_token},
+        random::random_bytes,
+    },
+};
+
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+/// Number of steps of clock drift accepted on either side of the current one.
+const SKEW: u8 = 1;
+const RECOVERY_CODES: usize = 10;
+
+/// Maximum number of wrong codes accepted for one login challenge.
+pub const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+/// Time left to submit the second factor after the password was accepted.
+pub const CHALLENGE_TTL_SECS: i64 = 300;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotpSettings {
+    /// Base32 encoded shared secret.
+    pub secret: String,
+    /// Enrollment is only effective once a first code has been verified.
+    pub confirmed: bool,
+    /// Hashes of the unused recovery codes.
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last accepted code, so a code cannot be replayed.
+    pub last_used_step: Option<u64>,
+}
+
+impl TotpSettings {
+    pub fn new() -> Self {
+        Self {
+            secret: Secret::Raw(random_bytes(20)).to_encoded().to_string(),
+            confirmed: false,
+            recovery_codes: Vec::new(),
+            last_used_step: None,
+        }
+    }
+
+    pub fn otpauth_uri(&self, account: &str, issuer: &str) -> Option<String> {
+        totp(&self.secret, account, issuer).map(|totp| totp.get_url())
+    }
+
+    /// Returns the time step matched by `code`, if it is valid and more recent than the last
+    /// accepted one.
+    pub fn verify_code(&self, code: &str) -> Option<u64> {
+        let totp = totp(&self.secret, "", "")?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let current = now / STEP;
+        let code = code.trim();
+
+        (current.saturating_sub(SKEW as u64)..=current + SKEW as u64)
+            .filter(|step| self.last_used_step.is_none_or(|last| *step > last))
+            .find(|step| constant_time_eq(totp.generate(step * STEP).as_bytes(), code.as_bytes()))
+    }
+
+    /// Accepts a current code, which then cannot be used again, or consumes a recovery code.
+    pub fn use_code(&mut self, code: &str) -> bool {
+        if let Some(step) = self.verify_code(code) {
+            self.last_used_step = Some(step);
+            return true;
+        }
+        self.use_recovery_code(code)
+    }
+
+    /// Consumes a recovery code, returning whether it was one of the unused codes.
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hash = hash_token(&normalize_recovery_code(code));
+        match self
+            .recovery_codes
+            .iter()
+            .position(|c| constant_time_eq(c.as_bytes(), hash.as_bytes()))
+        {
+            Some(pos) => {
+                self.recovery_codes.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces the recovery codes and returns the new ones in plain text.
+    pub fn regenerate_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                let code = hex::encode(random_bytes(5));
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect();
+        self.recovery_codes = codes
+            .iter()
+            .map(|code| hash_token(&normalize_recovery_code(code)))
+            .collect();
+        codes
+    }
+}
+
+fn totp(secret: &str, account: &str, issuer: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    Some(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        DIGITS,
+        SKEW,
+        STEP,
+        secret,
+        Some(issuer.to_string()),
+        account.to_string(),
+    ))
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TotpLoginRequest {
+    pub challenge: Uuid,
+    /// Either a current TOTP code or one of the recovery codes.
+    pub code: String,
+}
+
+/// First step of a two-step login, returned instead of tokens when the user enrolled in TOTP.
+#[derive(Serialize)]
+pub struct TotpChallenge {
+    pub challenge: Uuid,
+    pub expires_in: i64,
+}
+
+/// Pending login waiting for its second factor.
+#[derive(Debug, Clone)]
+pub struct LoginChallenge {
+    pub user: UserInfo,
+    pub scopes: Vec<Permission>,
+    pub expires_at: DateTime<Utc>,
+    pub attempts: u32,
+    /// The account is restored once the code is accepted.
+    pub reactivate: bool,
+}
@@ -1,8 +1,35 @@
+use chrono::{DateTime, Utc};
+
+use crate::user::{
+    account::AccountStatus, oidc::ExternalIdentity, profile::UsernameChange, role::Role, totp::TotpSettings,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub id: u32,
     pub username: String,
     pub email: String,
-    pub password: String
+    pub email_verified: bool,
+    /// Requested new email address, which replaces `email` once verified.
+    pub pending_email: Option<String>,
+    pub password: String,
+    pub roles: Vec<Role>,
+    pub totp: Option<TotpSettings>,
+    /// Accounts at external identity providers this user can log in with.
+    pub identities: Vec<ExternalIdentity>,
+    pub status: AccountStatus,
+    /// Previous usernames, oldest first. Entries are only ever appended.
+    pub username_history: Vec<UsernameChange>,
+    /// Time of the last successful sign-in, whatever the method.
+    pub last_login: Option<DateTime<Utc>>,
+}
+
+impl User {
+    pub fn has_totp(&self) -> bool {
+        self.totp.as_ref().is_some_and(|totp| totp.confirmed)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == AccountStatus::Active
+    }
 }
@@ -1,23 +1,61 @@
 use std::sync::{Arc, Mutex};
 
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
 
 use crate::user::{
-    Error::{InvalidCredentials, NotFound},
+    Error::{AlreadyExists, InvalidCredentials, NotFound},
     Result, errors,
 };
 use crate::{
-    user::user::User,
-    utils::password_handler::{hash_password, verify_password},
+    user::{
+        account::{AccountStatus, ERASED_USERNAME_PREFIX}, oidc::ExternalIdentity, profile::UsernameChange, role::Role, totp::TotpSettings,
+        user::User,
+    },
+    utils::{
+        password_handler::{hash_password, needs_rehash, verify_password},
+        random::random_token,
+    },
 };
 
+/// Hash verified against when the username is unknown, so that both failure cases take the same time.
+static DUMMY_HASH: Lazy<Option<String>> = Lazy::new(|| hash_password("dummy-password"));
+
 #[async_trait::async_trait]
 pub trait UserRepository: Send + Sync {
+    /// Fails with `AlreadyExists` when the username or, ignoring case, the email is taken.
     async fn add_user(&self, username: String, email: String, password: String) -> Result<User>;
     async fn get_user_by_username(&self, username: String) -> Result<User>;
+    async fn get_user_by_email(&self, email: String) -> Result<User>;
     async fn control_user(&self, username: String, password: String) -> Result<User>;
     async fn get_all_users(&self) -> Result<Vec<User>>;
     async fn get_user_by_id(&self, id: u32) -> Result<User>;
+    async fn set_roles(&self, id: u32, roles: Vec<Role>) -> Result<User>;
+    async fn set_totp(&self, id: u32, totp: Option<TotpSettings>) -> Result<User>;
+    /// Checks a code of a user with two-factor authentication enabled and consumes it, in one
+    /// step so that concurrent requests cannot both get the same code accepted.
+    async fn use_totp_code(&self, id: u32, code: &str) -> Result<bool>;
+    /// Replaces the stored hash; callers hash the new password themselves.
+    async fn update_password(&self, id: u32, password_hash: String) -> Result<User>;
+    async fn set_email_verified(&self, id: u32, verified: bool) -> Result<User>;
+    async fn get_user_by_identity(&self, identity: &ExternalIdentity) -> Result<User>;
+    async fn add_identity(&self, id: u32, identity: ExternalIdentity) -> Result<User>;
+    async fn set_status(&self, id: u32, status: AccountStatus) -> Result<User>;
+    async fn record_login(&self, id: u32, at: DateTime<Utc>) -> Result<User>;
+    /// Renames a user, recording the previous username in its history. Fails with
+    /// `AlreadyExists` when another user currently holds the username.
+    async fn update_username(&self, id: u32, username: String) -> Result<User>;
+    /// Most recent former holder of a username, if any.
+    async fn get_user_by_previous_username(&self, username: String) -> Result<User>;
+    /// Fails with `AlreadyExists` when another user uses the address.
+    async fn set_pending_email(&self, id: u32, email: Option<String>) -> Result<User>;
+    /// User waiting to switch to `email`, ignoring case.
+    async fn get_user_by_pending_email(&self, email: String) -> Result<User>;
+    /// Replaces the email address by the pending one, which is then verified.
+    async fn confirm_pending_email(&self, id: u32) -> Result<User>;
+    /// Replaces the personal data of a user with placeholders derived from its id and marks it
+    /// erased. The id itself is kept for the records referencing it.
+    async fn anonymize(&self, id: u32) -> Result<User>;
 }
 
 
@@ -50,17 +88,15 @@ impl UserRepository for MemoryUserRepository {
                 "Username and email cannot be empty".to_string(),
             ));
         }
-        if password.len() < 8 {
-            return Err(crate::user::Error::InvalidInput(
-                "Password must be at least 8 characters long".to_string(),
-            ));
-        }
         if users.iter().any(|u| u.username == username) {
             return Err(crate::user::Error::AlreadyExists(format!(
                 "User with username '{}' already exists",
                 username
             )));
         }
+        if users.iter().any(|u| u.email.eq_ignore_ascii_case(&email)) {
+            return Err(AlreadyExists(format!("Email '{}' is already in use", email)));
+        }
 
         let id = users.last().map_or(0, |u| u.id) + 1;
         match hash_password(&password) {
@@ -69,7 +105,15 @@ impl UserRepository for MemoryUserRepository {
                     id,
                     username: username.clone(),
                     email: email.clone(),
+                    email_verified: false,
+                    pending_email: None,
                     password: hashed_password,
+                    roles: vec![Role::Customer],
+                    totp: None,
+                    identities: Vec::new(),
+                    status: AccountStatus::Active,
+                    username_history: Vec::new(),
+                    last_login: None,
                 };
                 users.push(user.clone());
                 Ok(user)
@@ -89,19 +133,34 @@ impl UserRepository for MemoryUserRepository {
             .ok_or_else(|| NotFound(format!("User with username '{}' not found", username)))
     }
 
-    async fn control_user(&self, username: String, password: String) -> Result<User> {
+    async fn get_user_by_email(&self, email: String) -> Result<User> {
         let users = self.users.lock().unwrap();
-        let user = users
+        users
             .iter()
-            .find(|&u| u.username == username)
+            .find(|&u| u.email.eq_ignore_ascii_case(&email))
             .cloned()
-            .ok_or_else(|| {
-                InvalidCredentials(format!("User with username '{}' not found", username))
-            })?;
+            .ok_or_else(|| NotFound(format!("User with email '{}' not found", email)))
+    }
+
+    async fn control_user(&self, username: String, password: String) -> Result<User> {
+        let user = {
+            let users = self.users.lock().unwrap();
+            users.iter().find(|&u| u.username == username).cloned()
+        };
+        let Some(user) = user else {
+            if let Some(dummy) = DUMMY_HASH.as_ref() {
+                verify_password(&password, dummy.clone());
+            }
+            return Err(InvalidCredentials("Invalid username or password".to_string()));
+        };
 
         match verify_password(&password, user.password.clone()) {
+            Some(true) if needs_rehash(&user.password) => match hash_password(&password) {
+                Some(hashed_password) => self.update_password(user.id, hashed_password).await,
+                None => Ok(user),
+            },
             Some(true) => Ok(user),
-            Some(false) => Err(InvalidCredentials("Invalid password".to_string())),
+            Some(false) => Err(InvalidCredentials("Invalid username or password".to_string())),
             None => Err(errors::Error::HashingError(
                 "Password verification failed".to_string(),
             )),
@@ -121,4 +180,221 @@ impl UserRepository for MemoryUserRepository {
             .cloned()
             .ok_or_else(|| NotFound(format!("User with ID '{}' not found", id)))
     }
+
+    async fn set_roles(&self, id: u32, roles: Vec<Role>) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.roles = roles;
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn set_totp(&self, id: u32, totp: Option<TotpSettings>) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.totp = totp;
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn use_totp_code(&self, id: u32, code: &str) -> Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .iter_mut()
+            .find(|u| u.id == id)
+            .ok_or_else(|| NotFound(format!("User with ID '{}' not found", id)))?;
+        match user.totp.as_mut().filter(|totp| totp.confirmed) {
+            Some(totp) => Ok(totp.use_code(code)),
+            None => Err(NotFound("Two-factor authentication is not enabled".to_string())),
+        }
+    }
+
+    async fn update_password(&self, id: u32, password_hash: String) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.password = password_hash;
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn set_email_verified(&self, id: u32, verified: bool) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.email_verified = verified;
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn get_user_by_identity(&self, identity: &ExternalIdentity) -> Result<User> {
+        let users = self.users.lock().unwrap();
+        users
+            .iter()
+            .find(|u| u.identities.contains(identity))
+            .cloned()
+            .ok_or_else(|| {
+                NotFound(format!(
+                    "No user linked to '{}' at '{}'",
+                    identity.subject, identity.provider
+                ))
+            })
+    }
+
+    async fn add_identity(&self, id: u32, identity: ExternalIdentity) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|u| u.identities.contains(&identity)) {
+            return Err(crate::user::Error::AlreadyExists(format!(
+                "Identity '{}' at '{}' is already linked",
+                identity.subject, identity.provider
+            )));
+        }
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.identities.push(identity);
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn set_status(&self, id: u32, status: AccountStatus) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.status = status;
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn record_login(&self, id: u32, at: DateTime<Utc>) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.last_login = Some(at);
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn anonymize(&self, id: u32) -> Result<User> {
+        // Unusable password: nobody knows the random value it was derived from.
+        let password = hash_password(&random_token(32))
+            .ok_or_else(|| crate::user::Error::HashingError("Failed to hash password".to_string()))?;
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.username = format!("{}{}", ERASED_USERNAME_PREFIX, id);
+                user.email = format!("deleted-user-{}@invalid", id);
+                user.email_verified = false;
+                user.pending_email = None;
+                user.username_history = Vec::new();
+                user.password = password;
+                user.roles = Vec::new();
+                user.totp = None;
+                user.identities = Vec::new();
+                user.last_login = None;
+                user.status = AccountStatus::Erased;
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn update_username(&self, id: u32, username: String) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|u| u.id != id && u.username == username) {
+            return Err(AlreadyExists(format!(
+                "User with username '{}' already exists",
+                username
+            )));
+        }
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                if user.username != username {
+                    let previous = std::mem::replace(&mut user.username, username);
+                    user.username_history.push(UsernameChange {
+                        username: previous,
+                        changed_at: Utc::now(),
+                    });
+                }
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn get_user_by_previous_username(&self, username: String) -> Result<User> {
+        let users = self.users.lock().unwrap();
+        users
+            .iter()
+            .filter_map(|u| {
+                u.username_history
+                    .iter()
+                    .filter(|change| change.username == username)
+                    .map(|change| change.changed_at)
+                    .max()
+                    .map(|changed_at| (changed_at, u))
+            })
+            .max_by_key(|(changed_at, _)| *changed_at)
+            .map(|(_, u)| u.clone())
+            .ok_or_else(|| NotFound(format!("No user was previously named '{}'", username)))
+    }
+
+    async fn set_pending_email(&self, id: u32, email: Option<String>) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(email) = &email
+            && users.iter().any(|u| u.id != id && u.email.eq_ignore_ascii_case(email))
+        {
+            return Err(AlreadyExists(format!("Email '{}' is already in use", email)));
+        }
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(user) => {
+                user.pending_email = email;
+                Ok(user.clone())
+            }
+            None => Err(NotFound(format!("User with ID '{}' not found", id))),
+        }
+    }
+
+    async fn get_user_by_pending_email(&self, email: String) -> Result<User> {
+        let users = self.users.lock().unwrap();
+        users
+            .iter()
+            .find(|&u| u.pending_email.as_ref().is_some_and(|pending| pending.eq_ignore_ascii_case(&email)))
+            .cloned()
+            .ok_or_else(|| NotFound(format!("No user is switching to email '{}'", email)))
+    }
+
+    async fn confirm_pending_email(&self, id: u32) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        let email = users
+            .iter()
+            .find(|u| u.id == id)
+            .ok_or_else(|| NotFound(format!("User with ID '{}' not found", id)))?
+            .pending_email
+            .clone()
+            .ok_or_else(|| NotFound(format!("User with ID '{}' has no pending email", id)))?;
+        if users.iter().any(|u| u.id != id && u.email.eq_ignore_ascii_case(&email)) {
+            return Err(AlreadyExists(format!("Email '{}' is already in use", email)));
+        }
+        let user = users.iter_mut().find(|u| u.id == id).unwrap();
+        user.email = email;
+        user.email_verified = true;
+        user.pending_email = None;
+        Ok(user.clone())
+    }
 }
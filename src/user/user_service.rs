@@ -1,28 +1,128 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     sync::{Arc, Mutex},
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::user::{Error, user_repository::UserRepository};
+use crate::export::{self, personal_data::PersonalDataSource};
+use crate::mail::mailer::{Email, Mailer};
+use crate::user::{
+    Error,
+    account::{AccountStatus, ERASED_USERNAME_PREFIX, ScheduledDeletion},
+    api_key::{
+        API_KEY_PREFIX, ApiKey, ApiKeyInfo, ApiKeyRepository, CreateApiKeyRequest, CreatedApiKey,
+    },
+    config::{TokenMode, UserConfig},
+    jwt::JwkSet,
+    lockout::LoginThrottle,
+    login_history::{LoginAttempt, LoginHistoryRepository},
+    oidc::{
+        ExternalIdentity, IdTokenClaims, LOGIN_TTL_SECS, OidcCallbackQuery, OidcLogin,
+        OidcProvider, pkce_challenge,
+    },
+    password_policy::PasswordPolicy,
+    password_reset::{
+        ChangePasswordRequest, ForgotPasswordRequest, PasswordReset, ResetPasswordRequest,
+    },
+    permission::Permission,
+    profile::{PrivateProfile, PublicProfile, UpdateProfileRequest, UpdatedProfile},
+    role::Role,
+    session::{ClientInfo, RefreshToken, Session, SessionConfig, SessionInfo, session_deadline},
+    totp::{
+        CHALLENGE_TTL_SECS, LoginChallenge, MAX_CHALLENGE_ATTEMPTS, RecoveryCodes,
+        TotpChallenge, TotpEnrollment, TotpLoginRequest, TotpSettings,
+    },
+    user::User,
+    user_repository::UserRepository,
+    verification::{ResendVerificationRequest, VerificationConfig},
+};
+use crate::utils::{
+    password_handler::{hash_password, hash_token, verify_password},
+    random::random_token,
+};
+
+/// Usernames shadowed by fixed routes under `/users`.
+const RESERVED_USERNAMES: [&str; 1] = ["me"];
 
 #[derive(Clone)]
 pub struct UserService {
     repository: Arc<dyn UserRepository>,
-    tokens: Arc<Mutex<HashMap<Uuid, UserInfo>>>,
+    api_keys: Arc<dyn ApiKeyRepository>,
+    login_history: Arc<dyn LoginHistoryRepository>,
+    tokens: Arc<Mutex<HashMap<Uuid, Session>>>,
+    refresh_tokens: Arc<Mutex<HashMap<Uuid, RefreshToken>>>,
+    revoked_families: Arc<Mutex<HashMap<Uuid, DateTime<Utc>>>>,
+    login_throttle: Arc<LoginThrottle>,
+    login_challenges: Arc<Mutex<HashMap<Uuid, LoginChallenge>>>,
+    password_resets: Arc<Mutex<HashMap<String, PasswordReset>>>,
+    verification_sent: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+    oidc_providers: Arc<HashMap<String, OidcProvider>>,
+    oidc_logins: Arc<Mutex<HashMap<String, OidcLogin>>>,
+    mailer: Arc<dyn Mailer>,
+    session_config: SessionConfig,
+    verification: VerificationConfig,
+    password_policy: PasswordPolicy,
+    token_mode: TokenMode,
+    totp_issuer: String,
+    password_reset_ttl: Duration,
+    deletion_grace_period: Duration,
+    username_reservation: Duration,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserInfo {
+    pub id: u32,
     pub username: String,
     pub email: String,
+    pub roles: Vec<Role>,
     pub last_login: Option<DateTime<Utc>>,
 }
 
-#[derive(Deserialize, Serialize)]
+impl From<User> for UserInfo {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            roles: user.roles,
+            last_login: user.last_login,
+        }
+    }
+}
+
+impl UserInfo {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.iter().any(|r| r.satisfies(role))
+    }
+
+    pub fn permissions(&self) -> BTreeSet<Permission> {
+        self.roles
+            .iter()
+            .flat_map(|role| role.permissions().iter().copied())
+            .collect()
+    }
+}
+
+/// Identity resolved from an access token: the user and the scopes granted to that token.
+#[derive(Debug, Clone)]
+pub struct AuthContext {
+    pub user: UserInfo,
+    pub scopes: Vec<Permission>,
+    /// Token family (session) of the token; `None` for API keys.
+    pub family: Option<Uuid>,
+}
+
+impl AuthContext {
+    /// A scope is only effective while the user's current roles still allow it.
+    pub fn has_scope(&self, scope: Permission) -> bool {
+        self.scopes.contains(&scope) && self.user.permissions().contains(&scope)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct CreateUserRequest {
     pub username: String,
     pub email: String,
@@ -33,16 +133,90 @@ pub struct CreateUserRequest {
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
+    /// Scopes requested for the token; defaults to everything the user's roles allow.
+    #[serde(default)]
+    pub scope: Option<Vec<Permission>>,
+    /// Restores the account if it was deactivated or is scheduled for deletion; such accounts
+    /// are refused otherwise.
+    #[serde(default)]
+    pub reactivate: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: String,
+    pub scope: Vec<Permission>,
+    /// Set when the login restored an inactive account; not sent to the client.
+    #[serde(skip)]
+    pub reactivated: bool,
+}
+
+/// Outcome of a password login: tokens, or a challenge to answer with a TOTP code first.
+pub enum LoginResult {
+    Authenticated(TokenResponse),
+    TotpRequired(TotpChallenge),
 }
 
 impl UserService {
-    pub fn new(repository: Arc<dyn UserRepository>) -> Self {
+    pub fn new(
+        repository: Arc<dyn UserRepository>,
+        api_keys: Arc<dyn ApiKeyRepository>,
+        login_history: Arc<dyn LoginHistoryRepository>,
+        config: UserConfig,
+    ) -> Self {
         UserService {
             repository,
+            api_keys,
+            login_history,
             tokens: Arc::new(Mutex::new(HashMap::new())),
+            refresh_tokens: Arc::new(Mutex::new(HashMap::new())),
+            revoked_families: Arc::new(Mutex::new(HashMap::new())),
+            login_throttle: Arc::new(LoginThrottle::new(config.lockout)),
+            login_challenges: Arc::new(Mutex::new(HashMap::new())),
+            password_resets: Arc::new(Mutex::new(HashMap::new())),
+            verification_sent: Arc::new(Mutex::new(HashMap::new())),
+            oidc_providers: Arc::new(
+                config
+                    .oidc_providers
+                    .into_iter()
+                    .map(|provider| (provider.name.clone(), OidcProvider::new(provider)))
+                    .collect(),
+            ),
+            oidc_logins: Arc::new(Mutex::new(HashMap::new())),
+            mailer: config.mailer,
+            session_config: config.session,
+            verification: config.verification,
+            password_policy: config.password_policy,
+            token_mode: config.token_mode,
+            totp_issuer: config.totp_issuer,
+            password_reset_ttl: config.password_reset_ttl,
+            deletion_grace_period: config.deletion_grace_period,
+            username_reservation: config.username_reservation,
         }
     }
 
+    /// Periodically evicts expired sessions so the token map does not grow unbounded, and erases
+    /// the accounts whose deletion grace period has ended.
+    pub fn spawn_session_sweeper(service: Arc<Self>) {
+        let period = service.session_config.sweep_interval;
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(period);
+            loop {
+                interval.tick().await;
+                service.purge_expired_sessions();
+                service.erase_expired_accounts().await;
+            }
+        });
+    }
+
     pub async fn register_user(
         &self,
         request: CreateUserRequest,
@@ -50,60 +224,1303 @@ impl UserService {
         if request.username.is_empty() || request.email.is_empty() || request.password.is_empty() {
             return Err(Error::InvalidInput("Username, email, and password cannot be empty".to_string()));
         }
-        if request.password.len() < 8 {
-            return Err(Error::InvalidInput("Password must be at least 8 characters long".to_string()));
+        validate_username(&request.username)?;
+        validate_email(&request.email)?;
+        self.check_password_policy(&request.password, &request.username, &request.email).await?;
+        if self.username_reserved(&request.username, None).await {
+            return Err(Error::AlreadyExists(format!("Username '{}' is reserved", request.username)));
         }
         let user = self.repository.add_user(request.username, request.email, request.password).await?;
-        Ok(UserInfo {
-            username: user.username,
-            email: user.email,
-            last_login: None,
-        })
+        self.send_verification_email(&user, &user.email);
+        Ok(UserInfo::from(user))
     }
 
-    pub async fn login(&self, request : LoginRequest) -> Result<String, Error> {
+    pub async fn login(&self, request : LoginRequest, client: ClientInfo) -> Result<LoginResult, Error> {
         if request.username.is_empty() || request.password.is_empty() {
             return Err(Error::InvalidCredentials("Invalid username or password".to_string()));
         }
-        let user = self.repository.control_user(request.username, request.password).await?;
-        let token = Uuid::new_v4();
+        // Refused attempts are not added to the user's history, which they could otherwise flood.
+        let attempt = self.login_throttle.begin(&request.username, client.ip.as_deref())?;
+
+        let user = match self
+            .repository
+            .control_user(request.username.clone(), request.password)
+            .await
+        {
+            Ok(user) => user,
+            Err(Error::InvalidCredentials(message)) => {
+                attempt.failed();
+                let err = Error::InvalidCredentials(message);
+                self.record_failed_login(&request.username, &client, "password", &err).await;
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+        if user.has_totp() {
+            // Failures are only forgotten once the second factor passed too.
+            drop(attempt);
+        } else {
+            attempt.succeeded();
+        }
+        if let Err(err) = self.ensure_can_sign_in(&user, request.reactivate) {
+            self.record_login_attempt(user.id, &client, "password", Some(&err)).await;
+            return Err(err);
+        }
+
+        let user_info = UserInfo {
+            last_login: Some(Utc::now()),
+            ..UserInfo::from(user.clone())
+        };
+
+        let scopes = grant_scopes(&user_info, request.scope);
+        if user.has_totp() {
+            return Ok(self.totp_challenge(user_info, scopes, request.reactivate));
+        }
+
+        let reactivated = self.restore_on_login(&user, request.reactivate).await?;
+        self.record_login_attempt(user.id, &client, "password", None).await;
+        let mut tokens = self.issue_tokens(user_info, scopes, Uuid::new_v4(), client, Utc::now())?;
+        tokens.reactivated = reactivated;
+        Ok(LoginResult::Authenticated(tokens))
+    }
+
+    /// Parks a login whose first factor was accepted until `complete_totp_login` gets a code.
+    fn totp_challenge(&self, user: UserInfo, scopes: Vec<Permission>, reactivate: bool) -> LoginResult {
+        let challenge = Uuid::new_v4();
+        self.login_challenges.lock().unwrap().insert(
+            challenge,
+            LoginChallenge {
+                user,
+                scopes,
+                expires_at: Utc::now() + Duration::seconds(CHALLENGE_TTL_SECS),
+                attempts: 0,
+                reactivate,
+            },
+        );
+        LoginResult::TotpRequired(TotpChallenge {
+            challenge,
+            expires_in: CHALLENGE_TTL_SECS,
+        })
+    }
+
+    /// Second step of a login for users enrolled in TOTP. Accepts a current code or an unused
+    /// recovery code; a challenge is dropped once it expires or too many wrong codes were tried.
+    pub async fn complete_totp_login(
+        &self,
+        request: TotpLoginRequest,
+        client: ClientInfo,
+    ) -> Result<TokenResponse, Error> {
+        let invalid = || Error::InvalidCredentials("Invalid or expired challenge".to_string());
+        let challenge = {
+            let mut challenges = self.login_challenges.lock().unwrap();
+            let challenge = challenges.get_mut(&request.challenge).ok_or_else(invalid)?;
+            if challenge.expires_at <= Utc::now() || challenge.attempts >= MAX_CHALLENGE_ATTEMPTS {
+                challenges.remove(&request.challenge);
+                return Err(invalid());
+            }
+            challenge.attempts += 1;
+            challenge.clone()
+        };
+
+        let user = self.repository.get_user_by_id(challenge.user.id).await.map_err(|_| invalid())?;
+        if let Err(err) = ensure_active_or_restorable(&user, challenge.reactivate) {
+            self.record_login_attempt(user.id, &client, "totp", Some(&err)).await;
+            return Err(err);
+        }
+        let attempt = self.login_throttle.begin(&user.username, client.ip.as_deref())?;
+        if !self.repository.use_totp_code(user.id, &request.code).await.map_err(|_| invalid())? {
+            attempt.failed();
+            let err = Error::InvalidCredentials("Invalid code".to_string());
+            self.record_login_attempt(user.id, &client, "totp", Some(&err)).await;
+            return Err(err);
+        }
+        attempt.succeeded();
+        self.login_challenges.lock().unwrap().remove(&request.challenge);
+
+        let reactivated = self.restore_on_login(&user, challenge.reactivate).await?;
+        self.record_login_attempt(user.id, &client, "totp", None).await;
+        let mut tokens = self.issue_tokens(challenge.user, challenge.scopes, Uuid::new_v4(), client, Utc::now())?;
+        tokens.reactivated = reactivated;
+        Ok(tokens)
+    }
+
+    /// Exchanges a refresh token for a new token pair. The presented refresh token is rotated;
+    /// presenting it a second time is treated as theft and revokes the whole token family.
+    pub fn refresh(&self, request: RefreshRequest, client: ClientInfo) -> Result<TokenResponse, Error> {
+        let token = parse_token(&request.refresh_token)?;
+        let mut refresh_tokens = self.refresh_tokens.lock().unwrap();
+        let refresh_token = refresh_tokens
+            .get_mut(&token)
+            .ok_or_else(|| Error::InvalidCredentials("Unknown refresh token".to_string()))?;
+
+        if refresh_token.rotated {
+            let family = refresh_token.family;
+            drop(refresh_tokens);
+            self.revoke_family(family);
+            return Err(Error::InvalidCredentials("Refresh token reuse detected".to_string()));
+        }
+        if refresh_token.is_expired(Utc::now()) {
+            refresh_tokens.remove(&token);
+            return Err(Error::InvalidCredentials("Refresh token has expired".to_string()));
+        }
+        if session_deadline(refresh_token.created_at, &self.session_config) <= Utc::now() {
+            refresh_tokens.remove(&token);
+            return Err(Error::InvalidCredentials("Session has reached its maximum lifetime".to_string()));
+        }
+
+        refresh_token.rotated = true;
+        let user_info = refresh_token.user.clone();
+        let scopes = refresh_token.scopes.clone();
+        let family = refresh_token.family;
+        let created_at = refresh_token.created_at;
+        drop(refresh_tokens);
+
+        self.tokens.lock().unwrap().retain(|_, session| session.family != family);
+        self.issue_tokens(user_info, scopes, family, client, created_at)
+    }
+
+    fn issue_tokens(
+        &self,
+        user_info: UserInfo,
+        scopes: Vec<Permission>,
+        family: Uuid,
+        client: ClientInfo,
+        created_at: DateTime<Utc>,
+    ) -> Result<TokenResponse, Error> {
+        let deadline = session_deadline(created_at, &self.session_config);
+        let remaining = deadline - Utc::now();
+        let (access_token, expires_in) = match &self.token_mode {
+            TokenMode::Opaque => {
+                let token = Uuid::new_v4();
+                let session = Session::new(
+                    user_info.clone(),
+                    scopes.clone(),
+                    family,
+                    client.clone(),
+                    deadline,
+                );
+                self.tokens.lock().unwrap().insert(token, session);
+                (token.to_string(), self.session_config.idle_ttl.min(remaining).num_seconds())
+            }
+            TokenMode::Jwt(keys) => (
+                keys.issue(&user_info, &scopes, family, deadline)?,
+                keys.ttl().min(remaining).num_seconds(),
+            ),
+        };
+
+        let refresh_token = Uuid::new_v4();
+        let mut refresh_tokens = self.refresh_tokens.lock().unwrap();
+        refresh_tokens.insert(
+            refresh_token,
+            RefreshToken::new(
+                user_info,
+                scopes.clone(),
+                family,
+                client,
+                created_at,
+                &self.session_config,
+            ),
+        );
+
+        Ok(TokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in,
+            refresh_token: refresh_token.to_string(),
+            scope: scopes,
+            reactivated: false,
+        })
+    }
+
+    fn revoke_family(&self, family: Uuid) {
+        self.tokens.lock().unwrap().retain(|_, session| session.family != family);
+        self.refresh_tokens.lock().unwrap().retain(|_, token| token.family != family);
+        if let TokenMode::Jwt(keys) = &self.token_mode {
+            let mut revoked = self.revoked_families.lock().unwrap();
+            revoked.insert(family, Utc::now() + keys.ttl());
+        }
+    }
+
+    pub fn authenticate(&self, token: &str) -> Result<AuthContext, Error> {
+        let TokenMode::Jwt(keys) = &self.token_mode else {
+            return self.authenticate_session(token);
+        };
+
+        let claims = keys.verify(token)?;
+        if self.revoked_families.lock().unwrap().contains_key(&claims.family()?) {
+            return Err(Error::InvalidCredentials("Token has been revoked".to_string()));
+        }
+        claims.auth_context()
+    }
+
+    fn authenticate_session(&self, token: &str) -> Result<AuthContext, Error> {
+        let token = parse_token(token)?;
+        let now = Utc::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        let session = tokens
+            .get_mut(&token)
+            .ok_or_else(|| Error::InvalidCredentials("Unknown token".to_string()))?;
+
+        if session.is_expired(now, &self.session_config) {
+            tokens.remove(&token);
+            return Err(Error::InvalidCredentials("Token has expired".to_string()));
+        }
+        session.last_seen = now;
+        Ok(AuthContext {
+            user: session.user.clone(),
+            scopes: session.scopes.clone(),
+            family: Some(session.family),
+        })
+    }
+
+    /// Lists the logins of a user, one per token family still holding a live refresh token.
+    /// With opaque tokens the last activity is tracked per request, with signed tokens it is
+    /// the time of the last refresh.
+    pub fn list_sessions(&self, user_id: u32, current: Option<Uuid>) -> Vec<SessionInfo> {
+        let now = Utc::now();
+        let tokens = self.tokens.lock().unwrap();
+        let refresh_tokens = self.refresh_tokens.lock().unwrap();
+
+        let mut sessions: Vec<SessionInfo> = refresh_tokens
+            .values()
+            .filter(|token| token.user.id == user_id && !token.rotated && !token.is_expired(now))
+            .map(|token| {
+                let session = tokens.values().find(|session| session.family == token.family);
+                SessionInfo {
+                    id: token.family,
+                    client: session.map_or(&token.client, |session| &session.client).clone(),
+                    created_at: token.created_at,
+                    issued_at: session.map_or(token.issued_at, |session| session.issued_at),
+                    last_seen: session.map_or(token.issued_at, |session| session.last_seen),
+                    expires_at: token.expires_at,
+                    current: current == Some(token.family),
+                }
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        sessions
+    }
+
+    pub fn revoke_session(&self, user_id: u32, id: Uuid) -> Result<(), Error> {
+        let owned = self
+            .refresh_tokens
+            .lock()
+            .unwrap()
+            .values()
+            .any(|token| token.family == id && token.user.id == user_id);
+        if !owned {
+            return Err(Error::NotFound(format!("Session with id {} not found", id)));
+        }
+        self.revoke_family(id);
+        Ok(())
+    }
+
+    /// Revokes every session of a user and returns how many were revoked.
+    pub fn revoke_all_sessions(&self, user_id: u32) -> usize {
+        self.revoke_other_sessions(user_id, None)
+    }
+
+    /// Revokes the sessions of a user except the one of token family `keep`.
+    fn revoke_other_sessions(&self, user_id: u32, keep: Option<Uuid>) -> usize {
+        let mut families: BTreeSet<Uuid> = self
+            .tokens
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.user.id == user_id)
+            .map(|session| session.family)
+            .collect();
+        families.extend(
+            self.refresh_tokens
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|token| token.user.id == user_id && !token.rotated)
+                .map(|token| token.family),
+        );
+        if let Some(keep) = keep {
+            families.remove(&keep);
+        }
+
+        for family in &families {
+            self.revoke_family(*family);
+        }
+        families.len()
+    }
+
+    /// Revokes the given token together with the refresh tokens of the same login. Signed tokens
+    /// cannot be withdrawn from other instances, so their family is kept in a local deny list
+    /// until every access token of that family would have expired anyway.
+    pub fn logout(&self, token: &str) -> Result<(), Error> {
+        let family = match &self.token_mode {
+            TokenMode::Jwt(keys) => keys.verify(token)?.family()?,
+            TokenMode::Opaque => {
+                let token = parse_token(token)?;
+                let tokens = self.tokens.lock().unwrap();
+                tokens
+                    .get(&token)
+                    .map(|session| session.family)
+                    .ok_or_else(|| Error::InvalidCredentials("Unknown token".to_string()))?
+            }
+        };
+        self.revoke_family(family);
+        Ok(())
+    }
+
+    pub fn purge_expired_sessions(&self) -> usize {
+        let now = Utc::now();
         let mut tokens = self.tokens.lock().unwrap();
-        let last_login = Some(Utc::now());
-        tokens.insert(
-            token, 
-            UserInfo {
-                username: user.username.clone(),
-                email: user.email.clone(),
-                last_login,
+        let before = tokens.len();
+        tokens.retain(|_, session| !session.is_expired(now, &self.session_config));
+
+        let mut refresh_tokens = self.refresh_tokens.lock().unwrap();
+        refresh_tokens.retain(|_, token| !token.is_expired(now));
+
+        let mut revoked = self.revoked_families.lock().unwrap();
+        revoked.retain(|_, until| *until > now);
+
+        let mut challenges = self.login_challenges.lock().unwrap();
+        challenges.retain(|_, challenge| challenge.expires_at > now);
+
+        let mut password_resets = self.password_resets.lock().unwrap();
+        password_resets.retain(|_, reset| !reset.is_expired(now));
+
+        let mut verification_sent = self.verification_sent.lock().unwrap();
+        verification_sent.retain(|_, sent_at| *sent_at + self.verification.resend_interval > now);
+
+        let mut oidc_logins = self.oidc_logins.lock().unwrap();
+        oidc_logins.retain(|_, login| login.expires_at > now);
+
+        self.login_throttle.purge();
+        before - tokens.len()
+    }
+
+    /// Public keys used to sign access tokens. Empty when opaque tokens or only symmetric keys are in use.
+    pub fn jwks(&self) -> JwkSet {
+        match &self.token_mode {
+            TokenMode::Opaque => JwkSet { keys: Vec::new() },
+            TokenMode::Jwt(keys) => keys.jwks(),
+        }
+    }
+
+    pub async fn create_api_key(
+        &self,
+        user: &UserInfo,
+        request: CreateApiKeyRequest,
+    ) -> Result<CreatedApiKey, Error> {
+        if request.name.trim().is_empty() {
+            return Err(Error::InvalidInput("API key name cannot be empty".to_string()));
+        }
+        if request.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            return Err(Error::InvalidInput("API key expiry must be in the future".to_string()));
+        }
+
+        let id = Uuid::new_v4();
+        let secret = random_token(32);
+        let hash = hash_password(&secret)
+            .ok_or_else(|| Error::HashingError("Failed to hash API key".to_string()))?;
+        let key = self
+            .api_keys
+            .add_api_key(ApiKey {
+                id,
+                user_id: user.id,
+                name: request.name,
+                hash,
+                scopes: grant_scopes(user, request.scope),
+                created_at: Utc::now(),
+                expires_at: request.expires_at,
+            })
+            .await?;
+
+        Ok(CreatedApiKey {
+            key: format!("{}{}_{}", API_KEY_PREFIX, id.simple(), secret),
+            info: key.into(),
+        })
+    }
+
+    pub async fn list_api_keys(&self, user_id: u32) -> Result<Vec<ApiKeyInfo>, Error> {
+        let keys = self.api_keys.get_api_keys_by_user(user_id).await?;
+        Ok(keys.into_iter().map(ApiKeyInfo::from).collect())
+    }
+
+    pub async fn revoke_api_key(&self, user_id: u32, id: Uuid) -> Result<(), Error> {
+        let key = self.api_keys.get_api_key(id).await?;
+        if key.user_id != user_id {
+            return Err(Error::NotFound(format!("API key with id {} not found", id)));
+        }
+        self.api_keys.delete_api_key(id).await
+    }
+
+    pub async fn authenticate_api_key(&self, key: &str) -> Result<AuthContext, Error> {
+        let invalid = || Error::InvalidCredentials("Invalid API key".to_string());
+        let (id, secret) = key
+            .strip_prefix(API_KEY_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+            .ok_or_else(invalid)?;
+        let id = Uuid::parse_str(id).map_err(|_| invalid())?;
+
+        let api_key = self.api_keys.get_api_key(id).await.map_err(|_| invalid())?;
+        if api_key.is_expired(Utc::now()) {
+            return Err(Error::InvalidCredentials("API key has expired".to_string()));
+        }
+        // Argon2 takes tens of milliseconds, too long to hold a request worker on every call.
+        let (secret, hash) = (secret.to_string(), api_key.hash.clone());
+        let verified = actix_web::web::block(move || verify_password(&secret, hash))
+            .await
+            .map_err(|_| invalid())?;
+        if verified != Some(true) {
+            return Err(invalid());
+        }
+
+        let user = self.repository.get_user_by_id(api_key.user_id).await.map_err(|_| invalid())?;
+        if !user.is_active() {
+            return Err(invalid());
+        }
+        Ok(AuthContext {
+            user: UserInfo::from(user),
+            scopes: api_key.scopes,
+            family: None,
+        })
+    }
+
+    /// Starts an authorization code flow with PKCE and returns the provider URL to redirect the
+    /// browser to, along with the state the callback must come back with.
+    pub async fn start_oidc_login(&self, provider: &str, login_hint: Option<&str>) -> Result<(String, String), Error> {
+        let provider = self.oidc_provider(provider)?;
+        let state = random_token(16);
+        let nonce = random_token(16);
+        let code_verifier = random_token(32);
+        let url = provider
+            .authorization_url(&state, &nonce, &pkce_challenge(&code_verifier), login_hint)
+            .await?;
+
+        self.oidc_logins.lock().unwrap().insert(
+            state.clone(),
+            OidcLogin {
+                provider: provider.config().name.clone(),
+                code_verifier,
+                nonce,
+                expires_at: Utc::now() + Duration::seconds(LOGIN_TTL_SECS),
             },
         );
-        Ok(token.to_string())
+        Ok((url, state))
     }
 
-    pub async fn get_user_info(&self, username: String) -> Result<UserInfo, Error> {
-        let user = self.repository.get_user_by_username(username).await?;
-        Ok(UserInfo {
+    /// Handles the redirect back from the provider: redeems the code, validates the ID token,
+    /// then signs in the linked user, linking or creating one on the first login. Users enrolled
+    /// in TOTP still have to complete the second factor.
+    pub async fn complete_oidc_login(
+        &self,
+        provider: &str,
+        query: OidcCallbackQuery,
+        client: ClientInfo,
+    ) -> Result<LoginResult, Error> {
+        let provider = self.oidc_provider(provider)?;
+        let login = self
+            .oidc_logins
+            .lock()
+            .unwrap()
+            .remove(&query.state)
+            .filter(|login| login.provider == provider.config().name && login.expires_at > Utc::now())
+            .ok_or_else(|| Error::InvalidCredentials("Invalid or expired login state".to_string()))?;
+        if let Some(error) = query.error {
+            return Err(Error::InvalidCredentials(format!("Identity provider returned '{}'", error)));
+        }
+        let code = query
+            .code
+            .ok_or_else(|| Error::InvalidInput("Missing authorization code".to_string()))?;
+
+        let id_token = provider.exchange_code(&code, &login.code_verifier).await?;
+        let claims = provider.verify_id_token(&id_token, &login.nonce).await?;
+        let user = self.find_or_provision_user(provider, &claims).await?;
+        let method = format!("oidc:{}", provider.config().name);
+        if let Err(err) = self.ensure_can_sign_in(&user, false) {
+            self.record_login_attempt(user.id, &client, &method, Some(&err)).await;
+            return Err(err);
+        }
+
+        let user_info = UserInfo {
+            last_login: Some(Utc::now()),
+            ..UserInfo::from(user.clone())
+        };
+        let scopes = grant_scopes(&user_info, None);
+        if user.has_totp() {
+            return Ok(self.totp_challenge(user_info, scopes, false));
+        }
+        self.record_login_attempt(user_info.id, &client, &method, None).await;
+        let tokens = self.issue_tokens(user_info, scopes, Uuid::new_v4(), client, Utc::now())?;
+        Ok(LoginResult::Authenticated(tokens))
+    }
+
+    /// Sign-in attempts on the account of a user, most recent first.
+    pub async fn login_history(&self, user_id: u32) -> Result<Vec<LoginAttempt>, Error> {
+        self.login_history.get_attempts_by_user(user_id).await
+    }
+
+    /// Adds an attempt to the history of a user, and stamps the user's last login when it
+    /// succeeded. Failures are logged rather than failing the login itself.
+    async fn record_login_attempt(&self, user_id: u32, client: &ClientInfo, method: &str, failure: Option<&Error>) {
+        let now = Utc::now();
+        if failure.is_none()
+            && let Err(err) = self.repository.record_login(user_id, now).await
+        {
+            eprintln!("Failed to record last login of user {}: {:?}", user_id, err);
+        }
+        let attempt = LoginAttempt {
+            user_id,
+            timestamp: now,
+            client: client.clone(),
+            method: method.to_string(),
+            success: failure.is_none(),
+            failure_reason: failure.map(failure_reason),
+        };
+        if let Err(err) = self.login_history.add_attempt(attempt).await {
+            eprintln!("Failed to record login attempt of user {}: {:?}", user_id, err);
+        }
+    }
+
+    /// Records a failed attempt on the account holding `username`, if there is one.
+    async fn record_failed_login(&self, username: &str, client: &ClientInfo, method: &str, err: &Error) {
+        if let Ok(user) = self.repository.get_user_by_username(username.to_string()).await {
+            self.record_login_attempt(user.id, client, method, Some(err)).await;
+        }
+    }
+
+    fn ensure_can_sign_in(&self, user: &User, reactivate: bool) -> Result<(), Error> {
+        ensure_active_or_restorable(user, reactivate)?;
+        if !user.email_verified && !self.verification.allow_unverified_login {
+            return Err(Error::EmailNotVerified("Email address has not been verified".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Self-service counterpart of `reactivate_user`, run once every factor of a login that asked
+    /// for it was accepted. Returns whether the account had to be restored.
+    async fn restore_on_login(&self, user: &User, reactivate: bool) -> Result<bool, Error> {
+        if !reactivate || user.is_active() {
+            return Ok(false);
+        }
+        self.repository.set_status(user.id, AccountStatus::Active).await?;
+        Ok(true)
+    }
+
+    fn oidc_provider(&self, name: &str) -> Result<&OidcProvider, Error> {
+        self.oidc_providers
+            .get(name)
+            .ok_or_else(|| Error::NotFound(format!("Unknown identity provider '{}'", name)))
+    }
+
+    /// Resolves the local user of an external identity. An unknown identity is linked to the
+    /// account holding the same email address only if the provider verified that address;
+    /// otherwise a new account is created when the provider allows it.
+    async fn find_or_provision_user(&self, provider: &OidcProvider, claims: &IdTokenClaims) -> Result<User, Error> {
+        let config = provider.config();
+        let identity = ExternalIdentity {
+            provider: config.name.clone(),
+            subject: claims.sub.clone(),
+        };
+        if let Ok(user) = self.repository.get_user_by_identity(&identity).await {
+            return Ok(user);
+        }
+        // Only a verified address on both sides proves the provider account and the local one
+        // belong to the same person; anything else could be an account registered in advance
+        // with someone else's address.
+        if let Some(email) = &claims.email
+            && let Ok(user) = self.repository.get_user_by_email(email.clone()).await
+        {
+            if user.email_verified && claims.verified_email().is_some() {
+                return self.repository.add_identity(user.id, identity).await;
+            }
+            return Err(email_in_use());
+        }
+        if !config.auto_provision {
+            return Err(Error::InvalidCredentials("No account is linked to this identity".to_string()));
+        }
+
+        let email = claims
+            .email
+            .clone()
+            .ok_or_else(|| Error::InvalidInput("Identity provider did not share an email address".to_string()))?;
+        let base = claims
+            .preferred_username
+            .clone()
+            .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+        // Names users cannot pick are not handed out through the provider either.
+        let base = if validate_username(&base).is_ok() { base } else { "user".to_string() };
+        let mut user = None;
+        for attempt in 1..=100 {
+            let username = if attempt == 1 { base.clone() } else { format!("{}{}", base, attempt) };
+            if self.username_reserved(&username, None).await {
+                continue;
+            }
+            // Random password: the account can only sign in through the provider until a
+            // password is set with the reset flow.
+            match self.repository.add_user(username, email.clone(), random_token(32)).await {
+                Ok(created) => {
+                    user = Some(created);
+                    break;
+                }
+                Err(Error::AlreadyExists(_)) if self.repository.get_user_by_email(email.clone()).await.is_ok() => {
+                    return Err(email_in_use());
+                }
+                Err(Error::AlreadyExists(_)) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        let user = user.ok_or_else(|| Error::AlreadyExists(format!("No free username derived from '{}'", base)))?;
+
+        self.repository.set_roles(user.id, vec![config.default_role]).await?;
+        if claims.email_verified {
+            self.repository.set_email_verified(user.id, true).await?;
+        }
+        self.repository.add_identity(user.id, identity).await
+    }
+
+    /// Marks the email address of the user a verification link was sent to as verified. A link
+    /// sent to a pending address makes it the user's email address.
+    pub async fn verify_email(&self, token: &str) -> Result<UserInfo, Error> {
+        let invalid = || Error::InvalidCredentials("Invalid or expired verification link".to_string());
+        let id = VerificationConfig::user_id(token).ok_or_else(invalid)?;
+        let user = self.repository.get_user_by_id(id).await.map_err(|_| invalid())?;
+        let now = Utc::now();
+
+        let user = match &user.pending_email {
+            Some(pending) if self.verification.verify(token, pending, now) => {
+                let user = self.repository.confirm_pending_email(user.id).await?;
+                self.refresh_session_users(&user);
+                user
+            }
+            _ if self.verification.verify(token, &user.email, now) => {
+                self.repository.set_email_verified(user.id, true).await?
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(UserInfo::from(user))
+    }
+
+    /// Sends a new verification link, for the address of an account or one it is switching to.
+    /// Requests are limited per address, whether or not it belongs to an account, and unknown or
+    /// already verified addresses are silently ignored.
+    pub async fn resend_verification(&self, request: ResendVerificationRequest) -> Result<(), Error> {
+        let email = request.email.trim().to_lowercase();
+        if email.is_empty() {
+            return Err(Error::InvalidInput("Email cannot be empty".to_string()));
+        }
+        let now = Utc::now();
+        {
+            let mut verification_sent = self.verification_sent.lock().unwrap();
+            if let Some(sent_at) = verification_sent.get(&email) {
+                let retry_at = *sent_at + self.verification.resend_interval;
+                if retry_at > now {
+                    return Err(Error::TooManyAttempts((retry_at - now).num_seconds().max(1)));
+                }
+            }
+            verification_sent.insert(email.clone(), now);
+        }
+
+        if let Ok(user) = self.repository.get_user_by_email(email.clone()).await {
+            if !user.email_verified {
+                self.send_verification_email(&user, &user.email);
+            }
+        } else if let Ok(user) = self.repository.get_user_by_pending_email(email).await
+            && let Some(pending) = &user.pending_email
+        {
+            self.send_verification_email(&user, pending);
+        }
+        Ok(())
+    }
+
+    /// Sends a verification link for `email`, the user's address or the one they asked to
+    /// switch to.
+    fn send_verification_email(&self, user: &User, email: &str) {
+        self.verification_sent
+            .lock()
+            .unwrap()
+            .insert(email.to_lowercase(), Utc::now());
+        self.send_email(Email {
+            to: email.to_string(),
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Hello {},\n\nPlease confirm your email address by opening the following link within {} hours:\n\n{}\n\nIf you did not create an account, you can ignore this message.",
+                user.username,
+                self.verification.ttl.num_hours(),
+                self.verification.link(user.id, email)
+            ),
+        });
+    }
+
+    /// Delivers an email in the background; failures are logged, not reported to the caller.
+    fn send_email(&self, email: Email) {
+        let mailer = self.mailer.clone();
+        actix_web::rt::spawn(async move {
+            let subject = email.subject.clone();
+            if let Err(err) = mailer.send(email).await {
+                eprintln!("Failed to send email '{}': {:?}", subject, err);
+            }
+        });
+    }
+
+    /// Emails a single-use reset token to the owner of `email`. Unknown addresses and inactive
+    /// accounts are silently ignored and the email is sent in the background, so the response does not reveal
+    /// whether an account exists.
+    pub async fn forgot_password(&self, request: ForgotPasswordRequest) -> Result<(), Error> {
+        if request.email.trim().is_empty() {
+            return Err(Error::InvalidInput("Email cannot be empty".to_string()));
+        }
+        let Ok(user) = self.repository.get_user_by_email(request.email).await else {
+            return Ok(());
+        };
+        if !user.is_active() {
+            return Ok(());
+        }
+
+        let token = random_token(32);
+        let mut password_resets = self.password_resets.lock().unwrap();
+        password_resets.retain(|_, reset| reset.user_id != user.id);
+        password_resets.insert(
+            hash_token(&token),
+            PasswordReset {
+                user_id: user.id,
+                expires_at: Utc::now() + self.password_reset_ttl,
+            },
+        );
+        drop(password_resets);
+
+        let email = Email {
+            to: user.email,
+            subject: "Password reset".to_string(),
+            body: format!(
+                "Hello {},\n\nUse the following token to choose a new password. It expires in {} minutes and can only be used once.\n\n{}\n\nIf you did not ask for a password reset, you can ignore this message.",
+                user.username,
+                self.password_reset_ttl.num_minutes(),
+                token
+            ),
+        };
+        self.send_email(email);
+        Ok(())
+    }
+
+    /// Sets a new password from a reset token and signs the user out everywhere.
+    /// The token is only consumed once a password satisfying the policy was submitted.
+    pub async fn reset_password(&self, request: ResetPasswordRequest) -> Result<UserInfo, Error> {
+        let invalid = || Error::InvalidCredentials("Invalid or expired reset token".to_string());
+        let token = hash_token(&request.token);
+        let reset = self
+            .password_resets
+            .lock()
+            .unwrap()
+            .get(&token)
+            .filter(|reset| !reset.is_expired(Utc::now()))
+            .cloned()
+            .ok_or_else(invalid)?;
+        let user = self.repository.get_user_by_id(reset.user_id).await.map_err(|_| invalid())?;
+        self.check_password_policy(&request.new_password, &user.username, &user.email).await?;
+        self.password_resets.lock().unwrap().remove(&token).ok_or_else(invalid)?;
+
+        let hash = hash_password(&request.new_password)
+            .ok_or_else(|| Error::HashingError("Failed to hash password".to_string()))?;
+        let user = self.repository.update_password(user.id, hash).await?;
+        self.revoke_all_sessions(user.id);
+        self.login_throttle.unlock(&user.username);
+        Ok(UserInfo::from(user))
+    }
+
+    /// Changes the password of a signed in user, who must prove knowledge of the current one.
+    /// Wrong current passwords count as failed logins. Every other session is signed out.
+    pub async fn change_password(
+        &self,
+        auth: &AuthContext,
+        request: ChangePasswordRequest,
+    ) -> Result<(), Error> {
+        if request.new_password == request.current_password {
+            return Err(Error::InvalidInput("New password must differ from the current one".to_string()));
+        }
+        let user = self.verify_current_password(auth, request.current_password).await?;
+        self.check_password_policy(&request.new_password, &user.username, &user.email).await?;
+
+        let hash = hash_password(&request.new_password)
+            .ok_or_else(|| Error::HashingError("Failed to hash password".to_string()))?;
+        self.repository.update_password(user.id, hash).await?;
+        self.revoke_other_sessions(user.id, auth.family);
+        Ok(())
+    }
+
+    /// Re-authenticates a signed in user. Wrong passwords count as failed logins.
+    async fn verify_current_password(&self, auth: &AuthContext, password: String) -> Result<User, Error> {
+        let username = auth.user.username.clone();
+        let attempt = self.login_throttle.begin(&username, None)?;
+        let user = match self.repository.control_user(username, password).await {
+            Ok(user) => user,
+            Err(Error::InvalidCredentials(_)) => {
+                attempt.failed();
+                return Err(Error::InvalidCredentials("Current password is incorrect".to_string()));
+            }
+            Err(err) => return Err(err),
+        };
+        attempt.succeeded();
+        Ok(user)
+    }
+
+    async fn check_password_policy(&self, password: &str, username: &str, email: &str) -> Result<(), Error> {
+        self.password_policy
+            .check(password, username, email)
+            .await
+            .map_err(Error::WeakPassword)
+    }
+
+    /// Starts (or restarts) a TOTP enrollment. The new secret only takes effect once confirmed
+    /// with `confirm_totp`, so an abandoned enrollment never locks the user out.
+    pub async fn begin_totp_enrollment(&self, user_id: u32) -> Result<TotpEnrollment, Error> {
+        let user = self.repository.get_user_by_id(user_id).await?;
+        if user.has_totp() {
+            return Err(Error::AlreadyExists("Two-factor authentication is already enabled".to_string()));
+        }
+
+        let totp = TotpSettings::new();
+        let otpauth_uri = totp
+            .otpauth_uri(&user.username, &self.totp_issuer)
+            .ok_or_else(|| Error::HashingError("Failed to build TOTP URI".to_string()))?;
+        let secret = totp.secret.clone();
+        self.repository.set_totp(user.id, Some(totp)).await?;
+        Ok(TotpEnrollment { secret, otpauth_uri })
+    }
+
+    /// Activates a pending enrollment with a first valid code and returns the recovery codes,
+    /// which are only ever shown here.
+    pub async fn confirm_totp(&self, user_id: u32, code: &str) -> Result<RecoveryCodes, Error> {
+        let user = self.repository.get_user_by_id(user_id).await?;
+        let mut totp = match user.totp {
+            Some(totp) if !totp.confirmed => totp,
+            Some(_) => return Err(Error::AlreadyExists("Two-factor authentication is already enabled".to_string())),
+            None => return Err(Error::NotFound("No pending TOTP enrollment".to_string())),
+        };
+        let step = totp
+            .verify_code(code)
+            .ok_or_else(|| Error::InvalidInput("Invalid code".to_string()))?;
+
+        totp.confirmed = true;
+        totp.last_used_step = Some(step);
+        let recovery_codes = totp.regenerate_recovery_codes();
+        self.repository.set_totp(user.id, Some(totp)).await?;
+        Ok(RecoveryCodes { recovery_codes })
+    }
+
+    /// Turns two-factor authentication off; requires a current code or a recovery code.
+    pub async fn disable_totp(&self, user_id: u32, code: &str) -> Result<(), Error> {
+        if !self.repository.use_totp_code(user_id, code).await? {
+            return Err(Error::InvalidInput("Invalid code".to_string()));
+        }
+        self.repository.set_totp(user_id, None).await?;
+        Ok(())
+    }
+
+    /// Clears the failed login counter of a user, lifting a lockout.
+    pub async fn unlock_user(&self, id: u32) -> Result<(), Error> {
+        let user = self.repository.get_user_by_id(id).await?;
+        self.login_throttle.unlock(&user.username);
+        Ok(())
+    }
+
+    /// Makes sure the configured bootstrap account exists and holds the admin role.
+    pub async fn ensure_admin(&self, request: CreateUserRequest) -> Result<UserInfo, Error> {
+        let user = match self.repository.get_user_by_username(request.username.clone()).await {
+            Ok(user) => user,
+            Err(Error::NotFound(_)) => {
+                self.check_password_policy(&request.password, &request.username, &request.email).await?;
+                self.repository
+                    .add_user(request.username, request.email, request.password)
+                    .await?
+            }
+            Err(err) => return Err(err),
+        };
+        if !user.email_verified {
+            self.repository.set_email_verified(user.id, true).await?;
+        }
+        self.grant_role(user.id, Role::Admin).await
+    }
+
+    /// Self-service variant of `deactivate_user`, `delete_user` and `erase_user`: the user must
+    /// confirm with their current password.
+    pub async fn confirm_account_action(&self, auth: &AuthContext, password: String) -> Result<(), Error> {
+        self.verify_current_password(auth, password).await.map(|_| ())
+    }
+
+    /// Blocks logins and signs the user out everywhere. API keys are kept but rejected while the
+    /// account is inactive.
+    pub async fn deactivate_user(&self, id: u32) -> Result<UserInfo, Error> {
+        let user = self.repository.get_user_by_id(id).await?;
+        if user.status != AccountStatus::Active {
+            return Err(Error::InvalidInput("Only active accounts can be deactivated".to_string()));
+        }
+        let user = self.repository.set_status(id, AccountStatus::Deactivated).await?;
+        self.revoke_all_sessions(id);
+        Ok(UserInfo::from(user))
+    }
+
+    /// Restores a deactivated account, or a deleted one whose grace period is not over yet.
+    pub async fn reactivate_user(&self, id: u32) -> Result<UserInfo, Error> {
+        let user = self.repository.get_user_by_id(id).await?;
+        match user.status {
+            AccountStatus::Deactivated | AccountStatus::PendingDeletion { .. } => {}
+            AccountStatus::Active => {
+                return Err(Error::InvalidInput("Account is already active".to_string()));
+            }
+            AccountStatus::Erased => {
+                return Err(Error::InvalidInput("Erased accounts cannot be restored".to_string()));
+            }
+        }
+        let user = self.repository.set_status(id, AccountStatus::Active).await?;
+        Ok(UserInfo::from(user))
+    }
+
+    /// Soft delete: the account is disabled at once and erased when the grace period ends.
+    pub async fn delete_user(&self, id: u32) -> Result<ScheduledDeletion, Error> {
+        let user = self.repository.get_user_by_id(id).await?;
+        match user.status {
+            AccountStatus::Active | AccountStatus::Deactivated => {}
+            AccountStatus::PendingDeletion { .. } => {
+                return Err(Error::InvalidInput("Account is already scheduled for deletion".to_string()));
+            }
+            AccountStatus::Erased => {
+                return Err(Error::InvalidInput("Account has already been erased".to_string()));
+            }
+        }
+        let erase_after = Utc::now() + self.deletion_grace_period;
+        self.repository
+            .set_status(id, AccountStatus::PendingDeletion { erase_after })
+            .await?;
+        self.revoke_all_sessions(id);
+        Ok(ScheduledDeletion { erase_after })
+    }
+
+    /// Erases an account right away: signs it out, drops its API keys and pending tokens, and
+    /// anonymizes its personal data. The user id stays allocated for the records pointing at it.
+    pub async fn erase_user(&self, id: u32) -> Result<UserInfo, Error> {
+        let user = self.repository.get_user_by_id(id).await?;
+        if user.status == AccountStatus::Erased {
+            return Err(Error::InvalidInput("Account has already been erased".to_string()));
+        }
+        self.revoke_all_sessions(id);
+        for key in self.api_keys.get_api_keys_by_user(id).await? {
+            self.api_keys.delete_api_key(key.id).await?;
+        }
+        self.password_resets.lock().unwrap().retain(|_, reset| reset.user_id != id);
+        self.login_challenges.lock().unwrap().retain(|_, challenge| challenge.user.id != id);
+        self.verification_sent.lock().unwrap().remove(&user.email.to_lowercase());
+        self.login_throttle.unlock(&user.username);
+        self.login_history.delete_attempts_by_user(id).await?;
+
+        let user = self.repository.anonymize(id).await?;
+        Ok(UserInfo::from(user))
+    }
+
+    /// Erases the accounts whose deletion grace period has ended and returns how many were erased.
+    pub async fn erase_expired_accounts(&self) -> usize {
+        let now = Utc::now();
+        let Ok(users) = self.repository.get_all_users().await else {
+            return 0;
+        };
+        let mut erased = 0;
+        for user in users {
+            if let AccountStatus::PendingDeletion { erase_after } = user.status
+                && erase_after <= now
+            {
+                match self.erase_user(user.id).await {
+                    Ok(_) => erased += 1,
+                    Err(err) => eprintln!("Failed to erase account {}: {:?}", user.id, err),
+                }
+            }
+        }
+        erased
+    }
+
+    pub async fn grant_role(&self, id: u32, role: Role) -> Result<UserInfo, Error> {
+        let mut user = self.repository.get_user_by_id(id).await?;
+        if !user.roles.contains(&role) {
+            user.roles.push(role);
+        }
+        self.update_roles(user).await
+    }
+
+    pub async fn revoke_role(&self, id: u32, role: Role) -> Result<UserInfo, Error> {
+        let mut user = self.repository.get_user_by_id(id).await?;
+        user.roles.retain(|r| *r != role);
+        self.update_roles(user).await
+    }
+
+    /// Persists the roles and propagates them to the user's live sessions, so that changes apply
+    /// without requiring a new login. Signed tokens keep their roles until they expire.
+    async fn update_roles(&self, user: User) -> Result<UserInfo, Error> {
+        let user = self.repository.set_roles(user.id, user.roles).await?;
+        self.refresh_session_users(&user);
+        Ok(UserInfo::from(user))
+    }
+
+    /// Copies the username, email and roles of a user into its live sessions.
+    fn refresh_session_users(&self, user: &User) {
+        let update = |info: &mut UserInfo| {
+            info.username = user.username.clone();
+            info.email = user.email.clone();
+            info.roles = user.roles.clone();
+        };
+        let mut tokens = self.tokens.lock().unwrap();
+        for session in tokens.values_mut().filter(|s| s.user.id == user.id) {
+            update(&mut session.user);
+        }
+        let mut refresh_tokens = self.refresh_tokens.lock().unwrap();
+        for token in refresh_tokens.values_mut().filter(|t| t.user.id == user.id) {
+            update(&mut token.user);
+        }
+    }
+
+    /// Changes the username and/or email address of a user. A new username must not be held by
+    /// anyone else nor have been given up by another account within the reservation period. A
+    /// new email address only replaces the current one once verified; the current address is
+    /// told about the request.
+    pub async fn update_profile(&self, id: u32, request: UpdateProfileRequest) -> Result<UpdatedProfile, Error> {
+        let mut user = self.repository.get_user_by_id(id).await?;
+        let username = request
+            .username
+            .map(|username| username.trim().to_string())
+            .filter(|username| *username != user.username);
+        let email = request.email.map(|email| email.trim().to_string());
+
+        if let Some(username) = &username {
+            validate_username(username)?;
+        }
+        if let Some(email) = &email
+            && !email.eq_ignore_ascii_case(&user.email)
+        {
+            validate_email(email)?;
+            if self.repository.get_user_by_email(email.clone()).await.is_ok_and(|other| other.id != id) {
+                return Err(Error::AlreadyExists(format!("Email '{}' is already in use", email)));
+            }
+        }
+
+        if let Some(username) = username {
+            if self.username_reserved(&username, Some(id)).await {
+                return Err(Error::AlreadyExists(format!("Username '{}' is reserved", username)));
+            }
+            user = self.repository.update_username(id, username).await?;
+            self.refresh_session_users(&user);
+        }
+
+        if let Some(email) = email {
+            if email.eq_ignore_ascii_case(&user.email) {
+                if user.pending_email.is_some() {
+                    user = self.repository.set_pending_email(id, None).await?;
+                }
+            } else if user.pending_email.as_ref().is_none_or(|pending| !pending.eq_ignore_ascii_case(&email)) {
+                user = self.repository.set_pending_email(id, Some(email.clone())).await?;
+                self.send_verification_email(&user, &email);
+                self.send_email(Email {
+                    to: user.email.clone(),
+                    subject: "Email address change requested".to_string(),
+                    body: format!(
+                        "Hello {},\n\nA change of the email address of your account to {} was requested. It takes effect once the new address is confirmed.\n\nIf you did not ask for this change, reset your password right away.",
+                        user.username, email
+                    ),
+                });
+            }
+        }
+
+        Ok(UpdatedProfile {
+            pending_email: user.pending_email.clone(),
+            user: UserInfo::from(user),
+        })
+    }
+
+    /// Whether `username` was given up by another account than `user_id` less than the
+    /// reservation period ago.
+    async fn username_reserved(&self, username: &str, user_id: Option<u32>) -> bool {
+        let Ok(previous) = self.repository.get_user_by_previous_username(username.to_string()).await else {
+            return false;
+        };
+        if Some(previous.id) == user_id {
+            return false;
+        }
+        previous
+            .username_history
+            .iter()
+            .filter(|change| change.username == username)
+            .any(|change| change.changed_at + self.username_reservation > Utc::now())
+    }
+
+    /// Full profile of the caller.
+    pub async fn get_private_profile(&self, caller: &UserInfo) -> Result<PrivateProfile, Error> {
+        let user = self.repository.get_user_by_id(caller.id).await?;
+        Ok(PrivateProfile {
+            id: user.id,
+            totp_enabled: user.has_totp(),
             username: user.username,
             email: user.email,
-            last_login: None,
+            email_verified: user.email_verified,
+            pending_email: user.pending_email,
+            roles: user.roles,
+            last_login: user.last_login,
+            status: user.status,
         })
     }
 
+    pub async fn get_user_info(&self, username: String) -> Result<UserInfo, Error> {
+        let user = self.repository.get_user_by_username(username).await?;
+        Ok(UserInfo::from(user))
+    }
+
+    /// Directory shown to non-admin users: active accounts only, with their public fields.
+    pub async fn get_public_profiles(&self) -> Result<Vec<PublicProfile>, Error> {
+        let users = self.repository.get_all_users().await?;
+        Ok(users
+            .into_iter()
+            .filter(User::is_active)
+            .map(|user| PublicProfile {
+                id: user.id,
+                username: user.username,
+            })
+            .collect())
+    }
+
+    /// Public view of one account, by id or username. Inactive accounts are reported as unknown.
+    pub async fn get_public_profile(&self, user: String) -> Result<PublicProfile, Error> {
+        let user = match user.parse::<u32>() {
+            Ok(id) => self.repository.get_user_by_id(id).await?,
+            Err(_) => self.repository.get_user_by_username(user).await?,
+        };
+        if !user.is_active() {
+            return Err(Error::NotFound("User not found".to_string()));
+        }
+        Ok(PublicProfile::from(UserInfo::from(user)))
+    }
+
     pub async fn get_all_users(&self) -> Result<Vec<UserInfo>, Error> {
         let users = self.repository.get_all_users().await?;
-        Ok(users.into_iter().map(|user| UserInfo {
-            username: user.username,
-            email: user.email,
-            last_login: None,
-        }).collect())
+        Ok(users.into_iter().map(UserInfo::from).collect())
     }
     
     pub async fn get_user_by_id(&self, id: u32) -> Result<UserInfo, Error> {
         let user = self.repository.get_user_by_id(id).await?;
-        Ok(UserInfo {
-            username: user.username,
-            email: user.email,
-            last_login: None,
-        })
+        Ok(UserInfo::from(user))
+    }
+}
+
+/// Profile, sessions and API keys of the user. Secrets such as the password hash, the TOTP
+/// secret and API key hashes are left out.
+#[async_trait::async_trait]
+impl PersonalDataSource for UserService {
+    fn section(&self) -> &'static str {
+        "account"
+    }
+
+    async fn export(&self, user: &UserInfo) -> export::Result<serde_json::Value> {
+        let source_error = |e: Error| export::Error::SourceError(format!("{:?}", e));
+        let user = self.repository.get_user_by_id(user.id).await.map_err(source_error)?;
+        let api_keys = self.list_api_keys(user.id).await.map_err(source_error)?;
+        let logins = self.login_history(user.id).await.map_err(source_error)?;
+        Ok(serde_json::json!({
+            "profile": {
+                "id": user.id,
+                "username": user.username,
+                "email": user.email,
+                "email_verified": user.email_verified,
+                "pending_email": user.pending_email,
+                "username_history": user.username_history,
+                "roles": user.roles,
+                "account": user.status,
+                "totp_enabled": user.has_totp(),
+                "identities": user.identities,
+                "last_login": user.last_login,
+            },
+            "sessions": self.list_sessions(user.id, None),
+            "api_keys": api_keys,
+            "logins": logins,
+        }))
+    }
+}
+
+fn parse_token(token: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(token).map_err(|_| Error::InvalidCredentials("Malformed token".to_string()))
+}
+
+/// Scopes granted to a new credential: the requested ones capped by the user's roles, or
+/// everything the roles allow when nothing specific was requested.
+fn grant_scopes(user: &UserInfo, requested: Option<Vec<Permission>>) -> Vec<Permission> {
+    let allowed = user.permissions();
+    match requested {
+        Some(requested) => requested
+            .into_iter()
+            .filter(|scope| allowed.contains(scope))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect(),
+        None => allowed.into_iter().collect(),
+    }
+}
+
+/// Only active accounts may sign in. Checked once the credentials were accepted, so the status
+/// is not revealed to someone who does not know them.
+fn ensure_active(user: &User) -> Result<(), Error> {
+    match user.status {
+        AccountStatus::Active => Ok(()),
+        AccountStatus::Deactivated => Err(Error::AccountDisabled("Account is deactivated".to_string())),
+        AccountStatus::PendingDeletion { .. } | AccountStatus::Erased => {
+            Err(Error::AccountDisabled("Account has been deleted".to_string()))
+        }
+    }
+}
+
+/// Like `ensure_active`, but lets a deactivated account, or a deleted one still in its grace
+/// period, through when its owner asked for it to be restored.
+fn ensure_active_or_restorable(user: &User, reactivate: bool) -> Result<(), Error> {
+    match user.status {
+        AccountStatus::Deactivated | AccountStatus::PendingDeletion { .. } if reactivate => Ok(()),
+        _ => ensure_active(user),
+    }
+}
+
+/// Usernames users may pick: placeholders of erased accounts are taken from their own namespace
+/// so that they can never collide with a live account, and `/users/{user}` must be able to tell
+/// a username from an id or from `/users/me`.
+fn validate_username(username: &str) -> Result<(), Error> {
+    if username.is_empty() {
+        return Err(Error::InvalidInput("Username cannot be empty".to_string()));
+    }
+    if username.parse::<u32>().is_ok() {
+        return Err(Error::InvalidInput("Username cannot be a number".to_string()));
+    }
+    if RESERVED_USERNAMES.iter().any(|reserved| username.eq_ignore_ascii_case(reserved)) {
+        return Err(Error::InvalidInput(format!("Username '{}' is reserved", username)));
+    }
+    if username.to_lowercase().starts_with(ERASED_USERNAME_PREFIX) {
+        return Err(Error::InvalidInput(format!(
+            "Usernames starting with '{}' are reserved",
+            ERASED_USERNAME_PREFIX
+        )));
+    }
+    Ok(())
+}
+
+/// Only a sanity check: whether the address works is settled by the verification email.
+fn validate_email(email: &str) -> Result<(), Error> {
+    if !email.contains('@') {
+        return Err(Error::InvalidInput("Invalid email address".to_string()));
+    }
+    Ok(())
+}
+
+/// Refusal of an identity provider sign-in whose email address belongs to a local account that
+/// cannot be linked automatically.
+fn email_in_use() -> Error {
+    Error::AlreadyExists(
+        "An account already uses this email address; sign in with your password instead".to_string(),
+    )
+}
+
+/// Reason shown in the login history for a refused sign-in.
+fn failure_reason(err: &Error) -> String {
+    match err {
+        Error::InvalidCredentials(message)
+        | Error::EmailNotVerified(message)
+        | Error::AccountDisabled(message) => message.clone(),
+        other => format!("{:?}", other),
     }
 }
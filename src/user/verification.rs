@@ -0,0 +1,106 @@
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::utils::{env::var_or, random::random_bytes};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct VerificationConfig {
+    /// Key signing verification links. Without `EMAIL_VERIFICATION_SECRET` a random key is
+    /// generated, so links do not survive a restart.
+    secret: Vec<u8>,
+    /// How long a verification link stays valid.
+    pub ttl: Duration,
+    /// Minimum delay between two verification emails for the same address.
+    pub resend_interval: Duration,
+    /// Whether accounts with an unverified email address may log in.
+    pub allow_unverified_login: bool,
+    /// Base URL the verification links point to.
+    pub public_url: String,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            secret: random_bytes(32),
+            ttl: Duration::days(1),
+            resend_interval: Duration::minutes(1),
+            allow_unverified_login: false,
+            public_url: "http://127.0.0.1:8081".to_string(),
+        }
+    }
+}
+
+impl VerificationConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            secret: std::env::var("EMAIL_VERIFICATION_SECRET")
+                .map(String::into_bytes)
+                .unwrap_or(default.secret),
+            ttl: Duration::seconds(var_or("EMAIL_VERIFICATION_TTL_SECS", default.ttl.num_seconds())),
+            resend_interval: Duration::seconds(var_or(
+                "EMAIL_VERIFICATION_RESEND_SECS",
+                default.resend_interval.num_seconds(),
+            )),
+            allow_unverified_login: var_or("ALLOW_UNVERIFIED_LOGIN", default.allow_unverified_login),
+            public_url: var_or("PUBLIC_URL", default.public_url),
+        }
+    }
+
+    /// Link proving ownership of `email`, as `<user id>.<expiry>.<signature>`. The address is
+    /// part of the signed data, so the link stops working if the email changes meanwhile.
+    pub fn link(&self, user_id: u32, email: &str) -> String {
+        let expires = (Utc::now() + self.ttl).timestamp();
+        let payload = format!("{}.{}", user_id, expires);
+        let signature = hex::encode(self.mac(&payload, email).finalize().into_bytes());
+        format!(
+            "{}/verify-email?token={}.{}",
+            self.public_url.trim_end_matches('/'),
+            payload,
+            signature
+        )
+    }
+
+    /// User id a token was issued for, before its signature has been checked.
+    pub fn user_id(token: &str) -> Option<u32> {
+        token.split('.').next()?.parse().ok()
+    }
+
+    pub fn verify(&self, token: &str, email: &str, now: DateTime<Utc>) -> bool {
+        let Some((payload, signature)) = token.rsplit_once('.') else {
+            return false;
+        };
+        let Some(expires) = payload
+            .split_once('.')
+            .and_then(|(_, expires)| expires.parse::<i64>().ok())
+        else {
+            return false;
+        };
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        expires > now.timestamp() && self.mac(payload, email).verify_slice(&signature).is_ok()
+    }
+
+    fn mac(&self, payload: &str, email: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac.update(b".");
+        mac.update(email.to_lowercase().as_bytes());
+        mac
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
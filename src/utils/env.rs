@@ -0,0 +1,8 @@
+use std::{env, str::FromStr};
+
+pub fn var_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
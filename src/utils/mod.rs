@@ -1 +1,3 @@
-pub mod password_handler;
\ No newline at end of file
+pub mod env;
+pub mod password_handler;
+pub mod random;
\ No newline at end of file
@@ -1,30 +1,224 @@
 use argon2::password_hash::rand_core::OsRng;
 use argon2::{
-    Argon2,
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, Version,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
 };
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+
+use crate::utils::env::var_or;
+
+/// Server-side secret mixed into hashes through Argon2's secret input. Its `id` is stored in
+/// the `keyid` field of each hash, so several peppers can coexist while rotating.
+#[derive(Clone)]
+pub struct Pepper {
+    pub id: String,
+    pub secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for Pepper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pepper").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// Argon2 settings used for new hashes. Existing hashes are verified with the parameters
+/// encoded in them, and upgraded on the next successful login when they differ.
+#[derive(Debug, Clone)]
+pub struct HashingConfig {
+    pub algorithm: Algorithm,
+    pub version: Version,
+    /// Memory cost in KiB.
+    pub m_cost: u32,
+    /// Number of iterations.
+    pub t_cost: u32,
+    /// Degree of parallelism.
+    pub p_cost: u32,
+    /// Every pepper hashes may have been created with.
+    pub peppers: Vec<Pepper>,
+    /// Pepper used for new hashes; `None` hashes without pepper.
+    pub active_pepper: Option<String>,
+}
+
+impl Default for HashingConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::default(),
+            version: Version::default(),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            peppers: Vec::new(),
+            active_pepper: None,
+        }
+    }
+}
+
+impl HashingConfig {
+    /// Reads `ARGON2_ALGORITHM` (`argon2id`, `argon2i` or `argon2d`), `ARGON2_VERSION` (`19` or
+    /// `16`), `ARGON2_M_COST`, `ARGON2_T_COST` and `ARGON2_P_COST`. Peppers come from
+    /// `PASSWORD_PEPPERS`, a comma separated list of `id:secret` entries with ids of at most 8
+    /// bytes; `PASSWORD_PEPPER_ID` selects the one for new hashes and defaults to the last.
+    pub fn from_env() -> Result<Self, String> {
+        let default = Self::default();
+        let algorithm = match std::env::var("ARGON2_ALGORITHM") {
+            Ok(name) => name
+                .parse()
+                .map_err(|_| format!("Unknown ARGON2_ALGORITHM '{}'", name))?,
+            Err(_) => default.algorithm,
+        };
+        let version = match var_or("ARGON2_VERSION", default.version as u32) {
+            0x13 => Version::V0x13,
+            0x10 => Version::V0x10,
+            other => return Err(format!("Unknown ARGON2_VERSION '{}'", other)),
+        };
+
+        let peppers = match std::env::var("PASSWORD_PEPPERS") {
+            Ok(spec) => parse_peppers(&spec)?,
+            Err(_) => Vec::new(),
+        };
+        let active_pepper = std::env::var("PASSWORD_PEPPER_ID")
+            .ok()
+            .or_else(|| peppers.last().map(|pepper| pepper.id.clone()));
+        if let Some(id) = &active_pepper
+            && !peppers.iter().any(|pepper| pepper.id == *id)
+        {
+            return Err(format!("Pepper '{}' is not part of PASSWORD_PEPPERS", id));
+        }
+
+        let config = Self {
+            algorithm,
+            version,
+            m_cost: var_or("ARGON2_M_COST", default.m_cost),
+            t_cost: var_or("ARGON2_T_COST", default.t_cost),
+            p_cost: var_or("ARGON2_P_COST", default.p_cost),
+            peppers,
+            active_pepper,
+        };
+        config.params()?;
+        Ok(config)
+    }
+
+    fn params(&self) -> Result<Params, String> {
+        let mut builder = ParamsBuilder::new();
+        builder.m_cost(self.m_cost).t_cost(self.t_cost).p_cost(self.p_cost);
+        if let Some(id) = &self.active_pepper {
+            builder.keyid(KeyId::new(id.as_bytes()).map_err(|e| format!("Invalid pepper id '{}': {}", id, e))?);
+        }
+        builder
+            .build()
+            .map_err(|e| format!("Invalid Argon2 parameters: {}", e))
+    }
+
+    fn pepper(&self, id: &[u8]) -> Option<&Pepper> {
+        self.peppers.iter().find(|pepper| pepper.id.as_bytes() == id)
+    }
+}
+
+fn parse_peppers(spec: &str) -> Result<Vec<Pepper>, String> {
+    spec.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| match entry.trim().split_once(':') {
+            Some((id, secret)) if !id.is_empty() && !secret.is_empty() => {
+                KeyId::new(id.as_bytes()).map_err(|e| format!("Invalid pepper id '{}': {}", id, e))?;
+                Ok(Pepper {
+                    id: id.to_string(),
+                    secret: secret.as_bytes().to_vec(),
+                })
+            }
+            _ => Err("Invalid PASSWORD_PEPPERS entry, expected 'id:secret'".to_string()),
+        })
+        .collect()
+}
+
+static CONFIG: OnceCell<HashingConfig> = OnceCell::new();
+
+/// Sets the parameters used by `hash_password`. Only the first call has an effect; without
+/// one, the Argon2 defaults are used.
+pub fn configure(config: HashingConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static HashingConfig {
+    CONFIG.get_or_init(HashingConfig::default)
+}
+
+/// Argon2 context using the pepper identified by `keyid`, or none when `keyid` is empty.
+fn argon2(keyid: &[u8]) -> Option<Argon2<'static>> {
+    let config = config();
+    let params = config.params().unwrap_or_default();
+    if keyid.is_empty() {
+        return Some(Argon2::new(config.algorithm, config.version, params));
+    }
+    let pepper = config.pepper(keyid)?;
+    Argon2::new_with_secret(&pepper.secret, config.algorithm, config.version, params).ok()
+}
 
 pub fn hash_password(password: &str) -> Option<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+    let keyid = config().active_pepper.as_deref().unwrap_or_default();
 
-    match argon2.hash_password(password.as_bytes(), &salt) {
+    match argon2(keyid.as_bytes())?.hash_password(password.as_bytes(), &salt) {
         Ok(hash) => Some(hash.to_string()),
         Err(_) => None
     }
 }
 
+/// Verifies `password` against an Argon2 PHC string, or a bcrypt hash for imported accounts.
 pub fn verify_password(password: &str, origin: String) -> Option<bool> {
+    if is_bcrypt(&origin) {
+        return Some(bcrypt::verify(password, &origin).unwrap_or(false));
+    }
     match PasswordHash::new(&origin) {
         Ok(hash) => {
-            let argon2 = Argon2::default();
+            let keyid = Params::try_from(&hash).map(|params| params.keyid().to_vec()).ok()?;
+            let Some(argon2) = argon2(&keyid) else {
+                return Some(false);
+            };
             match argon2.verify_password(password.as_bytes(), &hash) {
-                Ok(_) => return Some(true),
-                Err(_) => return Some(false),
+                Ok(_) => Some(true),
+                Err(_) => Some(false),
             }
         }
-        Err(_) => {
-            return Some(false);
-        }
+        Err(_) => Some(false),
+    }
+}
+
+/// Whether a stored hash was produced with another algorithm or other parameters than the
+/// configured ones, and should be replaced once the password is known.
+pub fn needs_rehash(origin: &str) -> bool {
+    if is_bcrypt(origin) {
+        return true;
+    }
+    let Ok(hash) = PasswordHash::new(origin) else {
+        return true;
+    };
+    let config = config();
+    let Ok(params) = Params::try_from(&hash) else {
+        return true;
     };
+
+    hash.algorithm != config.algorithm.ident()
+        || hash.version != Some(config.version.into())
+        || params.m_cost() != config.m_cost
+        || params.t_cost() != config.t_cost
+        || params.p_cost() != config.p_cost
+        || params.keyid() != config.active_pepper.as_deref().unwrap_or_default().as_bytes()
+}
+
+fn is_bcrypt(origin: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| origin.starts_with(prefix))
+}
+
+/// Compares secrets in a time that does not depend on where they first differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Fast digest for high-entropy secrets such as recovery codes or reset tokens, which unlike
+/// user chosen passwords do not need a slow, salted hash.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
@@ -0,0 +1,13 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buffer = vec![0u8; len];
+    OsRng.fill_bytes(&mut buffer);
+    buffer
+}
+
+/// URL-safe random string built from `bytes` bytes of OS randomness.
+pub fn random_token(bytes: usize) -> String {
+    URL_SAFE_NO_PAD.encode(random_bytes(bytes))
+}
@@ -1,58 +1,298 @@
-// use actix_web::{
-//     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}};
-    
-// pub struct Authorization;
-
-// impl <S, B> Transform <S, ServiceRequest> for Authorization
-// where 
-//     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-//     B: 'static,
-// {
-//     type Response = ServiceResponse<B>;
-//     type Error = Error;
-//     type Transform = AuthorizationMiddleware<S>;
-//     type Future = Ready<Result<Self::Transform, Self::Error>>;
-
-//     fn new_transform(&self, service: S) -> Self::Future {
-//         ready(Ok(AuthorizationMiddleware { service }))
-//     }
-// }
-
-// pub struct AuthorizationMiddleware<S> {
-//     service: S,
-// }
-
-// impl<S, B> Service<ServiceRequest> for AuthorizationMiddleware<S>
-// where
-//     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-//     B: 'static,
-// {
-//     type Response = ServiceResponse<B>;
-//     type Error = Error;
-//     type Future = S::Future;
-
-//     forward_ready!(service);
-
-//     fn call(&self, req: ServiceRequest) -> Self::Future {
-//         // Here you can add your authorization logic
-//         // For example, checking headers or tokens
-
-//         let auth_header = req.headers().get("Authorization");
-//         if let Some(auth_value) = auth_header {
-//             if let Ok(token) = auth_value.to_str() {
-//                 // Validate the token (this is just a placeholder, implement your logic)
-
-                
-//                 if token == "valid_token" {
-//                     // Token is valid, proceed with the request
-//                     return self.service.call(req);
-//                 }
-//             }
-//         }
-//         // If the token is invalid or not present, return an error response
-//         let response = req.error_response(
-//             actix_web::HttpResponse::Unauthorized().body("Unauthorized")
-//         );
-//         return futures::future::ok(response);
-//     }
-// }
+use std::{
+    future::{Ready, ready},
+    rc::Rc,
+    sync::Arc,
+};
+
+use actix_web::{
+    Error, HttpMessage, HttpRequest, HttpResponse,
+    body::EitherBody,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    http::header,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+
+use crate::user::{
+    permission::Permission,
+    role::Role,
+    session::ClientInfo,
+    user_service::{AuthContext, UserInfo, UserService},
+};
+use crate::web::cookies::CookieConfig;
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl ErrorResponse {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self { error: error.into() }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InsufficientScopeResponse {
+    pub error: String,
+    pub missing_scope: Permission,
+}
+
+/// The raw bearer token (or session cookie) of the current request, stored next to the `UserInfo`.
+#[derive(Clone)]
+pub struct BearerToken(pub String);
+
+/// Header carrying a long-lived API key, accepted as an alternative to a bearer token.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Middleware validating `Authorization: Bearer <token>` against the sessions held by
+/// `UserService`, or an `X-Api-Key` header against the stored API keys. On success the resolved
+/// `UserInfo` and `AuthContext` are stored in the request extensions and can be extracted in
+/// handlers with `web::ReqData`. With cookie sessions enabled, the session cookie is accepted
+/// when neither header is present, and unsafe requests authenticated that way must pass the
+/// CSRF check.
+#[derive(Clone)]
+pub struct Authorization {
+    user_service: Arc<UserService>,
+    cookies: Arc<CookieConfig>,
+    optional: bool,
+}
+
+impl Authorization {
+    pub fn new(user_service: Arc<UserService>, cookies: CookieConfig) -> Self {
+        Self {
+            user_service,
+            cookies: Arc::new(cookies),
+            optional: false,
+        }
+    }
+
+    /// Variant letting anonymous requests through; a token that is present must still be valid.
+    /// A stale session cookie is ignored rather than rejected.
+    pub fn optional(&self) -> Self {
+        Self {
+            user_service: self.user_service.clone(),
+            cookies: self.cookies.clone(),
+            optional: true,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Authorization
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizationMiddleware {
+            service: Rc::new(service),
+            user_service: self.user_service.clone(),
+            cookies: self.cookies.clone(),
+            optional: self.optional,
+        }))
+    }
+}
+
+pub struct AuthorizationMiddleware<S> {
+    service: Rc<S>,
+    user_service: Arc<UserService>,
+    cookies: Arc<CookieConfig>,
+    optional: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthorizationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let user_service = self.user_service.clone();
+        let cookies = self.cookies.clone();
+        let optional = self.optional;
+
+        Box::pin(async move {
+            let token = bearer_token(&req);
+            let api_key = req
+                .headers()
+                .get(API_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let cookie = match (&token, &api_key) {
+                (None, None) => cookies.session_token(req.request()),
+                _ => None,
+            };
+
+            let result = match (&token, &api_key, &cookie) {
+                (Some(token), _, _) => user_service.authenticate(token),
+                (None, Some(api_key), _) => user_service.authenticate_api_key(api_key).await,
+                (None, None, Some(cookie)) => user_service.authenticate(cookie),
+                (None, None, None) if optional && !req.headers().contains_key(header::AUTHORIZATION) => {
+                    return service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_left_body);
+                }
+                (None, None, None) => {
+                    return Ok(unauthorized(req, "Missing bearer token or API key"));
+                }
+            };
+
+            match result {
+                Ok(_) if cookie.is_some() && !cookies.csrf_valid(req.request()) => {
+                    let response = HttpResponse::Forbidden()
+                        .json(ErrorResponse::new("Missing or invalid CSRF token"));
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+                Ok(auth) => {
+                    req.extensions_mut().insert(auth.user.clone());
+                    req.extensions_mut().insert(auth);
+                    if let Some(token) = token.or(cookie) {
+                        req.extensions_mut().insert(BearerToken(token));
+                    }
+                    service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_left_body)
+                }
+                Err(_) if optional && cookie.is_some() => service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_left_body),
+                Err(_) => Ok(unauthorized(req, "Invalid or expired credentials")),
+            }
+        })
+    }
+}
+
+/// Checks that the token behind the request was granted `scope`. Handlers call this first and
+/// return the error response as-is, which is a 403 naming the missing scope.
+pub fn require_scope(auth: &AuthContext, scope: Permission) -> Result<(), HttpResponse> {
+    if auth.has_scope(scope) {
+        return Ok(());
+    }
+    Err(HttpResponse::Forbidden()
+        .insert_header((
+            header::WWW_AUTHENTICATE,
+            format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope),
+        ))
+        .json(InsufficientScopeResponse {
+            error: format!("Missing scope '{}'", scope),
+            missing_scope: scope,
+        }))
+}
+
+/// Peer address and user agent of the request, recorded on sessions.
+pub fn client_info(req: &HttpRequest) -> ClientInfo {
+    ClientInfo {
+        ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+    }
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") || token.trim().is_empty() {
+        return None;
+    }
+    Some(token.trim().to_string())
+}
+
+fn unauthorized<B>(req: ServiceRequest, message: &str) -> ServiceResponse<EitherBody<B>> {
+    let response = HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .json(ErrorResponse::new(message));
+    req.into_response(response).map_into_right_body()
+}
+
+/// Middleware rejecting requests whose authenticated user does not hold `role` (or a role above
+/// it) with a JSON 403. It must run after `Authorization`, so wrap it first:
+/// `.wrap(RequireRole::new(Role::Editor)).wrap(authorization)`.
+#[derive(Clone)]
+pub struct RequireRole {
+    role: Role,
+}
+
+impl RequireRole {
+    pub fn new(role: Role) -> Self {
+        Self { role }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireRoleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware {
+            service: Rc::new(service),
+            role: self.role,
+        }))
+    }
+}
+
+pub struct RequireRoleMiddleware<S> {
+    service: Rc<S>,
+    role: Role,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let role = self.role;
+
+        Box::pin(async move {
+            let allowed = req
+                .extensions()
+                .get::<UserInfo>()
+                .map(|user_info| user_info.has_role(role));
+
+            match allowed {
+                Some(true) => service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_left_body),
+                Some(false) => {
+                    let response = HttpResponse::Forbidden()
+                        .json(ErrorResponse::new(format!("Requires role '{}'", role)));
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+                None => Ok(unauthorized(req, "Authentication required")),
+            }
+        })
+    }
+}
@@ -0,0 +1,160 @@
+use actix_web::{
+    HttpRequest,
+    cookie::{Cookie, SameSite, time::Duration},
+    http::Method,
+};
+use serde::Serialize;
+
+use crate::{
+    user::{oidc::LOGIN_TTL_SECS, permission::Permission, user_service::TokenResponse},
+    utils::{env::var_or, password_handler::{constant_time_eq, hash_token}},
+};
+
+/// Body of a login or refresh in cookie mode. The tokens themselves only travel in `HttpOnly`
+/// cookies, out of reach of page scripts.
+#[derive(Serialize)]
+pub struct CookieSessionResponse {
+    pub expires_in: i64,
+    pub scope: Vec<Permission>,
+    pub csrf_token: String,
+}
+
+/// Header carrying the CSRF token, which must match the CSRF cookie on unsafe requests
+/// authenticated by the session cookie (double-submit).
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Browser sessions: when enabled, logins also set the access and refresh tokens as
+/// `HttpOnly` cookies, next to a CSRF cookie readable by scripts.
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    pub enabled: bool,
+    pub session_name: String,
+    pub refresh_name: String,
+    pub csrf_name: String,
+    /// Binds an identity provider login to the browser that started it. Set whether or not
+    /// cookie sessions are enabled.
+    pub oidc_state_name: String,
+    /// Only disable for local development over plain HTTP.
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            session_name: "session".to_string(),
+            refresh_name: "refresh_token".to_string(),
+            csrf_name: "csrf_token".to_string(),
+            oidc_state_name: "oidc_state".to_string(),
+            secure: true,
+            same_site: SameSite::Lax,
+        }
+    }
+}
+
+impl CookieConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let default = Self::default();
+        let same_site = match var_or("SESSION_COOKIE_SAMESITE", "lax".to_string()).as_str() {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            other => return Err(format!("Unknown SESSION_COOKIE_SAMESITE '{}'", other)),
+        };
+        Ok(Self {
+            enabled: var_or("COOKIE_SESSIONS", default.enabled),
+            session_name: var_or("SESSION_COOKIE_NAME", default.session_name),
+            refresh_name: var_or("REFRESH_COOKIE_NAME", default.refresh_name),
+            csrf_name: var_or("CSRF_COOKIE_NAME", default.csrf_name),
+            oidc_state_name: var_or("OIDC_STATE_COOKIE_NAME", default.oidc_state_name),
+            secure: var_or("SESSION_COOKIE_SECURE", default.secure),
+            same_site,
+        })
+    }
+
+    /// Access token carried by the session cookie, if cookie sessions are enabled.
+    pub fn session_token(&self, req: &HttpRequest) -> Option<String> {
+        self.cookie_value(req, &self.session_name)
+    }
+
+    pub fn refresh_token(&self, req: &HttpRequest) -> Option<String> {
+        self.cookie_value(req, &self.refresh_name)
+    }
+
+    /// Safe methods never need a CSRF token; other requests must echo the CSRF cookie in the
+    /// `X-CSRF-Token` header.
+    pub fn csrf_valid(&self, req: &HttpRequest) -> bool {
+        if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+            return true;
+        }
+        let header = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok());
+        match (self.cookie_value(req, &self.csrf_name), header) {
+            (Some(cookie), Some(header)) => constant_time_eq(cookie.as_bytes(), header.as_bytes()),
+            _ => false,
+        }
+    }
+
+    /// Cookies for a freshly issued token pair, with the CSRF token scripts must echo.
+    pub fn login_cookies(&self, tokens: &TokenResponse, csrf_token: &str) -> Vec<Cookie<'static>> {
+        vec![
+            self.cookie(&self.session_name, tokens.access_token.clone(), "/", true),
+            self.cookie(&self.refresh_name, tokens.refresh_token.clone(), "/token/refresh", true),
+            self.cookie(&self.csrf_name, csrf_token.to_string(), "/", false),
+        ]
+    }
+
+    pub fn logout_cookies(&self) -> Vec<Cookie<'static>> {
+        [
+            (&self.session_name, "/"),
+            (&self.refresh_name, "/token/refresh"),
+            (&self.csrf_name, "/"),
+        ]
+        .into_iter()
+        .map(|(name, path)| {
+            let mut cookie = self.cookie(name, String::new(), path, true);
+            cookie.set_max_age(Duration::ZERO);
+            cookie
+        })
+        .collect()
+    }
+
+    /// Cookie holding a digest of the state of an identity provider login. Always `SameSite=Lax`,
+    /// the strictest mode under which it is still sent on the provider's redirect back.
+    pub fn oidc_state_cookie(&self, state: &str) -> Cookie<'static> {
+        Cookie::build(self.oidc_state_name.clone(), hash_token(state))
+            .path("/auth")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::seconds(LOGIN_TTL_SECS))
+            .finish()
+    }
+
+    /// Whether the callback carrying `state` comes back to the browser that started the login.
+    pub fn oidc_state_valid(&self, req: &HttpRequest, state: &str) -> bool {
+        req.cookie(&self.oidc_state_name)
+            .is_some_and(|cookie| constant_time_eq(cookie.value().as_bytes(), hash_token(state).as_bytes()))
+    }
+
+    fn cookie(&self, name: &str, value: String, path: &str, http_only: bool) -> Cookie<'static> {
+        Cookie::build(name.to_string(), value)
+            .path(path.to_string())
+            .http_only(http_only)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .finish()
+    }
+
+    fn cookie_value(&self, req: &HttpRequest, name: &str) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        req.cookie(name)
+            .map(|cookie| cookie.value().to_string())
+            .filter(|value| !value.is_empty())
+    }
+}
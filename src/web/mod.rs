@@ -1,3 +1,4 @@
+pub mod cookies;
 pub mod product_routes;
 pub mod user_routes;
 pub mod authorization;
\ No newline at end of file
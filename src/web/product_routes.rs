@@ -1,14 +1,21 @@
 use std::sync::Arc;
 
-use actix_web::{web, HttpResponse, Responder, Scope};
+use actix_web::{guard, web, HttpRequest, HttpResponse, Responder, Scope};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::audit::{
+    audit_event::{AuditEvent, AuditEventType},
+    audit_sink::{self, AuditSink},
+};
 use crate::products::products_repository::ProductRepository;
+use crate::user::{permission::Permission, role::Role, user_service::AuthContext};
+use crate::web::authorization::{client_info, require_scope, Authorization, RequireRole};
 
 #[derive(Clone)]
 pub struct ProductRoutes {
     products_repo: Arc<dyn ProductRepository>,
+    audit: Arc<dyn AuditSink>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -18,28 +25,47 @@ pub struct ProductRequest {
 }
 
 impl ProductRoutes {
-    pub fn new(repository: Arc<dyn ProductRepository>) -> Self {
+    pub fn new(repository: Arc<dyn ProductRepository>, audit: Arc<dyn AuditSink>) -> Self {
         Self {
             products_repo: repository,
+            audit,
         }
     }
 
-    pub fn scope(data: web::Data<Self>) -> Scope {
+    /// Catalog reads are public; when a token is presented it must carry `products:read`.
+    pub fn scope(data: web::Data<Self>, authorization: Authorization) -> Scope {
         web::scope("/products")
             .app_data(data.clone())
-            .route("", web::get().to(Self::list))
-            .route("/{id}", web::get().to(Self::get))
-            .route("", web::post().to(Self::create))
-            .route("/{id}", web::delete().to(Self::delete))
-            .route("/{id}", web::put().to(Self::update))
+            .service(
+                web::scope("")
+                    .guard(guard::Get())
+                    .wrap(authorization.optional())
+                    .route("", web::get().to(Self::list))
+                    .route("/{id}", web::get().to(Self::get)),
+            )
+            .service(
+                web::scope("")
+                    .guard(guard::Any(guard::Post()).or(guard::Put()).or(guard::Delete()))
+                    .wrap(RequireRole::new(Role::Editor))
+                    .wrap(authorization)
+                    .route("", web::post().to(Self::create))
+                    .route("/{id}", web::delete().to(Self::delete))
+                    .route("/{id}", web::put().to(Self::update)),
+            )
     }
 
-    async fn list(data: web::Data<Self>) -> impl Responder {
+    async fn list(data: web::Data<Self>, auth: Option<web::ReqData<AuthContext>>) -> impl Responder {
+        if let Some(Err(response)) = auth.map(|auth| require_scope(&auth, Permission::ProductsRead)) {
+            return response;
+        }
         let products = data.products_repo.get_products().await;
         HttpResponse::Ok().json(products.unwrap())
     }
 
-    async fn get(data: web::Data<Self>, id: web::Path<Uuid>) -> impl Responder {
+    async fn get(data: web::Data<Self>, auth: Option<web::ReqData<AuthContext>>, id: web::Path<Uuid>) -> impl Responder {
+        if let Some(Err(response)) = auth.map(|auth| require_scope(&auth, Permission::ProductsRead)) {
+            return response;
+        }
         match data.products_repo.get_product_by_id(*id).await {
             Ok(product) => HttpResponse::Ok().json(product),
             Err(err) => match err {
@@ -49,9 +75,12 @@ impl ProductRoutes {
         }
     }
 
-    async fn create(data: web::Data<Self>, item: web::Json<ProductRequest>) -> impl Responder {
+    async fn create(data: web::Data<Self>, auth: web::ReqData<AuthContext>, item: web::Json<ProductRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::ProductsWrite) {
+            return response;
+        }
         let new_product = item.into_inner();
-        let product = data.products_repo.add_product(new_product.name, new_product.price).await;
+        let product = data.products_repo.add_product(new_product.name, new_product.price, auth.user.id).await;
         match product {
             Ok(product) => HttpResponse::Created().json(product),
             Err(err) => match err {
@@ -62,7 +91,10 @@ impl ProductRoutes {
         }
     }
 
-    async fn update(data: web::Data<Self>, id: web::Path<Uuid>, item: web::Json<ProductRequest>) -> impl Responder {
+    async fn update(data: web::Data<Self>, auth: web::ReqData<AuthContext>, id: web::Path<Uuid>, item: web::Json<ProductRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::ProductsWrite) {
+            return response;
+        }
         let updated_product = item.into_inner();
         match data.products_repo.update_product(*id, updated_product.name, updated_product.price).await {
             Ok(product) => HttpResponse::Ok().json(product),
@@ -74,9 +106,21 @@ impl ProductRoutes {
         }
     }
 
-    async fn delete(data: web::Data<Self>, id: web::Path<Uuid>) -> impl Responder {
+    async fn delete(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, id: web::Path<Uuid>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::ProductsWrite) {
+            return response;
+        }
         match data.products_repo.delete_product(*id).await {
-            Ok(_) => HttpResponse::NoContent().finish(),
+            Ok(_) => {
+                audit_sink::record(
+                    data.audit.as_ref(),
+                    AuditEvent::new(AuditEventType::ProductDeleted, &client_info(&req))
+                        .actor(auth.user.id)
+                        .target(format!("product:{}", id)),
+                )
+                .await;
+                HttpResponse::NoContent().finish()
+            }
             Err(err) => match err {
                 crate::products::Error::NotFound(_) => HttpResponse::NotFound().finish(),
                 _ => HttpResponse::InternalServerError().finish(),
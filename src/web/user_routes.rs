@@ -1,84 +1,504 @@
 use std::sync::Arc;
 
-use actix_web::{HttpResponse, Responder, Scope, http::header::HeaderName, web};
+use serde::Serialize;
+use uuid::Uuid;
 
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, Responder, Scope, http::header::{self, HeaderName}, web};
+
+use crate::audit::{
+    audit_event::{AuditEvent, AuditEventType, AuditQuery, MAX_QUERY_LIMIT},
+    audit_sink::{self, AuditSink},
+};
+use crate::export::personal_data::{ExportFormat, ExportQuery, PersonalDataExporter};
+use crate::web::authorization::{client_info, require_scope, Authorization, BearerToken, ErrorResponse, RequireRole};
+use crate::web::cookies::{CookieConfig, CookieSessionResponse};
 use crate::user::{
-    Error::{AlreadyExists, InvalidCredentials, InvalidInput},
-    user_service::{CreateUserRequest, LoginRequest, UserService},
+    Error::{self, AccountDisabled, AlreadyExists, EmailNotVerified, InvalidCredentials, InvalidInput, NotFound, ProviderError, TooManyAttempts, WeakPassword},
+    account::ConfirmPasswordRequest,
+    api_key::CreateApiKeyRequest,
+    oidc::{OidcCallbackQuery, OidcStartQuery},
+    password_policy::PasswordViolation,
+    password_reset::{ChangePasswordRequest, ForgotPasswordRequest, ResetPasswordRequest},
+    permission::Permission,
+    profile::UpdateProfileRequest,
+    role::Role,
+    totp::{TotpCodeRequest, TotpLoginRequest},
+    verification::{ResendVerificationRequest, VerifyEmailQuery},
+    user_service::{AuthContext, CreateUserRequest, LoginRequest, LoginResult, RefreshRequest, TokenResponse, UserService},
 };
+use crate::utils::random::random_token;
+
+#[derive(Serialize)]
+struct WeakPasswordResponse {
+    error: String,
+    violations: Vec<PasswordViolation>,
+}
 
 pub struct UserRoutes {
     user_service: Arc<UserService>,
+    cookies: CookieConfig,
+    audit: Arc<dyn AuditSink>,
+    exporter: PersonalDataExporter,
 }
 
 impl UserRoutes {
-    pub fn new(user_service: Arc<UserService>) -> Self {
-        Self { user_service }
+    pub fn new(
+        user_service: Arc<UserService>,
+        cookies: CookieConfig,
+        audit: Arc<dyn AuditSink>,
+        exporter: PersonalDataExporter,
+    ) -> Self {
+        Self { user_service, cookies, audit, exporter }
     }
 
-    pub fn scope(data: web::Data<Self>) -> Scope {
+    pub fn scope(data: web::Data<Self>, authorization: Authorization) -> Scope {
         web::scope("")
             .app_data(data.clone())
             .route("register", web::post().to(Self::register))
             .route("login", web::post().to(Self::login))
-            .route("/users", web::get().to(Self::list_users))
-            .route("/users/{id}", web::get().to(Self::get_user))
-            .route("/users/{username}", web::get().to(Self::get_user_by_username))
+            .route("/login/totp", web::post().to(Self::login_totp))
+            .route("/token/refresh", web::post().to(Self::refresh))
+            .route("/verify-email", web::get().to(Self::verify_email))
+            .route("/verify-email/resend", web::post().to(Self::resend_verification))
+            .route("/password/forgot", web::post().to(Self::forgot_password))
+            .route("/password/reset", web::post().to(Self::reset_password))
+            .route("/.well-known/jwks.json", web::get().to(Self::jwks))
+            .route("/auth/{provider}/start", web::get().to(Self::oidc_start))
+            .route("/auth/{provider}/callback", web::get().to(Self::oidc_callback))
+            // Registered ahead of `/users`: an inactive account has no session to authenticate with.
+            .route("/users/me/account/reactivate", web::post().to(Self::reactivate_own_account))
+            .service(
+                web::resource("/logout")
+                    .wrap(authorization.clone())
+                    .route(web::post().to(Self::logout)),
+            )
+            .service(
+                web::scope("/audit")
+                    .wrap(RequireRole::new(Role::Admin))
+                    .wrap(authorization.clone())
+                    .route("", web::get().to(Self::audit_log)),
+            )
+            .service(
+                web::scope("/users")
+                    .wrap(authorization)
+                    .route("", web::get().to(Self::list_users))
+                    .service(
+                        web::scope("/me")
+                            .route("", web::get().to(Self::get_own_profile))
+                            .route("", web::patch().to(Self::update_own_profile))
+                            .route("/sessions", web::get().to(Self::list_sessions))
+                            .route("/logins", web::get().to(Self::list_logins))
+                            .route("/sessions/{session_id}", web::delete().to(Self::revoke_session))
+                            .route("/api-keys", web::get().to(Self::list_api_keys))
+                            .route("/api-keys", web::post().to(Self::create_api_key))
+                            .route("/api-keys/{key_id}", web::delete().to(Self::revoke_api_key))
+                            .route("/password", web::put().to(Self::change_password))
+                            .route("/totp", web::post().to(Self::enroll_totp))
+                            .route("/totp/confirm", web::post().to(Self::confirm_totp))
+                            .route("/totp", web::delete().to(Self::disable_totp))
+                            .route("/account/deactivate", web::post().to(Self::deactivate_own_account))
+                            .route("/account", web::delete().to(Self::delete_own_account))
+                            .route("/account/erase", web::post().to(Self::erase_own_account))
+                            .route("/export", web::get().to(Self::export_data)),
+                    )
+                    .route("/{user}", web::get().to(Self::get_user))
+                    .route("/{id}", web::patch().to(Self::update_profile))
+                    .service(
+                        web::scope("/{id}/lockout")
+                            .wrap(RequireRole::new(Role::Admin))
+                            .route("", web::delete().to(Self::unlock_user)),
+                    )
+                    .service(
+                        web::scope("/{id}/sessions")
+                            .wrap(RequireRole::new(Role::Admin))
+                            .route("", web::delete().to(Self::revoke_user_sessions)),
+                    )
+                    .service(
+                        web::scope("/{id}/account")
+                            .wrap(RequireRole::new(Role::Admin))
+                            .route("/deactivate", web::post().to(Self::deactivate_user))
+                            .route("/reactivate", web::post().to(Self::reactivate_user))
+                            .route("", web::delete().to(Self::delete_user))
+                            .route("/erase", web::post().to(Self::erase_user)),
+                    )
+                    .service(
+                        web::scope("/{id}/roles")
+                            .wrap(RequireRole::new(Role::Admin))
+                            .route("/{role}", web::put().to(Self::grant_role))
+                            .route("/{role}", web::delete().to(Self::revoke_role)),
+                    ),
+            )
     }
 
-    async fn register(data: web::Data<Self>, item: web::Json<CreateUserRequest>) -> impl Responder {
+    async fn register(data: web::Data<Self>, req: HttpRequest, item: web::Json<CreateUserRequest>) -> impl Responder {
         match data.user_service.register_user(item.into_inner()).await {
-            Ok(user_info) => HttpResponse::Created().json(user_info),
+            Ok(user_info) => {
+                data.audit(
+                    AuditEvent::new(AuditEventType::UserRegistered, &client_info(&req))
+                        .actor(user_info.id)
+                        .target_user(user_info.id),
+                )
+                .await;
+                HttpResponse::Created().json(user_info)
+            }
             Err(err) => match err {
                 AlreadyExists(_) => HttpResponse::Conflict().finish(),
+                WeakPassword(violations) => Self::weak_password(violations),
                 InvalidInput(_) => HttpResponse::BadRequest().finish(),
                 _ => HttpResponse::InternalServerError().finish(),
             },
         }
     }
 
-    async fn login(data: web::Data<Self>, item: web::Json<LoginRequest>) -> impl Responder {
-        match data.user_service.login(item.into_inner()).await {
-            Ok(token) => {
-                HttpResponse::Ok()
-                    .append_header(
-                        (HeaderName::from_static("authorization"), 
-                        format!("Bearer {}", token)
-                    ))
-                    .finish()
+    async fn login(data: web::Data<Self>, req: HttpRequest, item: web::Json<LoginRequest>) -> impl Responder {
+        let username = item.username.clone();
+        let result = data.user_service.login(item.into_inner(), client_info(&req)).await;
+        let failure = match &result {
+            Err(InvalidCredentials(reason) | EmailNotVerified(reason) | AccountDisabled(reason)) => Some(reason.as_str()),
+            Err(TooManyAttempts(_)) => Some("Too many failed login attempts"),
+            _ => None,
+        };
+        if let Some(reason) = failure {
+            data.audit_login_failure(&req, Some(&username), reason).await;
+        }
+        match result {
+            Ok(LoginResult::Authenticated(tokens)) => {
+                data.audit_login(&req, &tokens, "password").await;
+                data.token_response(tokens)
             }
+            Ok(LoginResult::TotpRequired(challenge)) => HttpResponse::Accepted().json(challenge),
             Err(err) => match err {
-                InvalidCredentials(_) => HttpResponse::Unauthorized().finish(),
+                InvalidCredentials(message) => HttpResponse::Unauthorized().json(ErrorResponse::new(message)),
+                TooManyAttempts(retry_after) => HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(ErrorResponse::new("Too many failed login attempts, try again later")),
+                EmailNotVerified(message) | AccountDisabled(message) => {
+                    HttpResponse::Forbidden().json(ErrorResponse::new(message))
+                }
                 InvalidInput(_) => HttpResponse::BadRequest().finish(),
                 _ => HttpResponse::InternalServerError().finish(),
             },
         }
     }
 
-    async fn list_users(data: web::Data<Self>) -> impl Responder {
-        match data.user_service.get_all_users().await {
-            Ok(users) => HttpResponse::Ok().json(users),
+    /// A login that restores the account when it was deactivated or is pending deletion.
+    async fn reactivate_own_account(data: web::Data<Self>, req: HttpRequest, item: web::Json<LoginRequest>) -> impl Responder {
+        let mut request = item.into_inner();
+        request.reactivate = true;
+        Self::login(data, req, web::Json(request)).await
+    }
+
+    async fn login_totp(data: web::Data<Self>, req: HttpRequest, item: web::Json<TotpLoginRequest>) -> impl Responder {
+        match data.user_service.complete_totp_login(item.into_inner(), client_info(&req)).await {
+            Ok(tokens) => {
+                data.audit_login(&req, &tokens, "totp").await;
+                data.token_response(tokens)
+            }
             Err(err) => match err {
-                InvalidInput(_) => HttpResponse::BadRequest().finish(),
+                InvalidCredentials(message) => {
+                    data.audit_login_failure(&req, None, &message).await;
+                    HttpResponse::Unauthorized().json(ErrorResponse::new(message))
+                }
+                AccountDisabled(message) => {
+                    data.audit_login_failure(&req, None, &message).await;
+                    HttpResponse::Forbidden().json(ErrorResponse::new(message))
+                }
+                TooManyAttempts(retry_after) => HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(ErrorResponse::new("Too many failed login attempts, try again later")),
                 _ => HttpResponse::InternalServerError().finish(),
             },
         }
     }
 
-    async fn get_user(data: web::Data<Self>, id: web::Path<u32>) -> impl Responder {
-        match data.user_service.get_user_by_id(*id).await {
-            Ok(user) => HttpResponse::Ok().json(user),
+    /// The refresh token comes from the body, or from the refresh cookie of a browser session.
+    async fn refresh(data: web::Data<Self>, req: HttpRequest, item: Option<web::Json<RefreshRequest>>) -> impl Responder {
+        let request = match item {
+            Some(item) => item.into_inner(),
+            None => match data.cookies.refresh_token(&req) {
+                Some(_) if !data.cookies.csrf_valid(&req) => {
+                    return HttpResponse::Forbidden().json(ErrorResponse::new("Missing or invalid CSRF token"));
+                }
+                Some(refresh_token) => RefreshRequest { refresh_token },
+                None => return HttpResponse::BadRequest().json(ErrorResponse::new("Missing refresh token")),
+            },
+        };
+        match data.user_service.refresh(request, client_info(&req)) {
+            Ok(tokens) => data.token_response(tokens),
             Err(err) => match err {
-                crate::user::Error::NotFound(_) => HttpResponse::NotFound().finish(),
-                InvalidInput(_) => HttpResponse::BadRequest().finish(),
+                InvalidCredentials(_) => HttpResponse::Unauthorized().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn oidc_start(data: web::Data<Self>, provider: web::Path<String>, query: web::Query<OidcStartQuery>) -> impl Responder {
+        match data.user_service.start_oidc_login(&provider, query.login_hint.as_deref()).await {
+            Ok((url, state)) => HttpResponse::Found()
+                .insert_header((header::LOCATION, url))
+                .cookie(data.cookies.oidc_state_cookie(&state))
+                .finish(),
+            Err(err) => match err {
+                NotFound(message) => HttpResponse::NotFound().json(ErrorResponse::new(message)),
+                ProviderError(message) => Self::provider_error(message),
                 _ => HttpResponse::InternalServerError().finish(),
             },
         }
     }
 
-    async fn get_user_by_username(data: web::Data<Self>, username: web::Path<String>) -> impl Responder {
-        match data.user_service.get_user_info(username.into_inner()).await {
+    /// Only accepted from the browser holding the state cookie set by `oidc_start`, so a callback
+    /// URL cannot be replayed in someone else's browser to sign them into another account.
+    async fn oidc_callback(data: web::Data<Self>, req: HttpRequest, provider: web::Path<String>, query: web::Query<OidcCallbackQuery>) -> impl Responder {
+        let mut response = if data.cookies.oidc_state_valid(&req, &query.state) {
+            data.complete_oidc_login(&req, &provider, query.into_inner()).await
+        } else {
+            let message = "Login was not started from this browser";
+            data.audit_login_failure(&req, None, &format!("oidc:{}: {}", provider, message)).await;
+            HttpResponse::Unauthorized().json(ErrorResponse::new(message))
+        };
+        if let Err(err) = response.add_removal_cookie(&data.cookies.oidc_state_cookie("")) {
+            eprintln!("Failed to clear the login state cookie: {}", err);
+        }
+        response
+    }
+
+    async fn complete_oidc_login(&self, req: &HttpRequest, provider: &str, query: OidcCallbackQuery) -> HttpResponse {
+        match self.user_service.complete_oidc_login(provider, query, client_info(req)).await {
+            Ok(LoginResult::Authenticated(tokens)) => {
+                self.audit_login(req, &tokens, &format!("oidc:{}", provider)).await;
+                self.token_response(tokens)
+            }
+            Ok(LoginResult::TotpRequired(challenge)) => HttpResponse::Accepted().json(challenge),
+            Err(err) => match err {
+                InvalidCredentials(message) => {
+                    self.audit_login_failure(req, None, &format!("oidc:{}: {}", provider, message)).await;
+                    HttpResponse::Unauthorized().json(ErrorResponse::new(message))
+                }
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                EmailNotVerified(message) | AccountDisabled(message) => {
+                    HttpResponse::Forbidden().json(ErrorResponse::new(message))
+                }
+                AlreadyExists(message) => HttpResponse::Conflict().json(ErrorResponse::new(message)),
+                NotFound(message) => HttpResponse::NotFound().json(ErrorResponse::new(message)),
+                ProviderError(message) => Self::provider_error(message),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    fn provider_error(message: String) -> HttpResponse {
+        eprintln!("Identity provider error: {}", message);
+        HttpResponse::BadGateway().json(ErrorResponse::new("Identity provider is unavailable"))
+    }
+
+    async fn verify_email(data: web::Data<Self>, query: web::Query<VerifyEmailQuery>) -> impl Responder {
+        match data.user_service.verify_email(&query.token).await {
             Ok(user_info) => HttpResponse::Ok().json(user_info),
+            Err(err) => match err {
+                InvalidCredentials(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn resend_verification(data: web::Data<Self>, item: web::Json<ResendVerificationRequest>) -> impl Responder {
+        match data.user_service.resend_verification(item.into_inner()).await {
+            Ok(_) => HttpResponse::Accepted().finish(),
+            Err(err) => match err {
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                TooManyAttempts(retry_after) => HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(ErrorResponse::new("A verification email was sent recently, try again later")),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn forgot_password(data: web::Data<Self>, item: web::Json<ForgotPasswordRequest>) -> impl Responder {
+        match data.user_service.forgot_password(item.into_inner()).await {
+            Ok(_) => HttpResponse::Accepted().finish(),
+            Err(err) => match err {
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn reset_password(data: web::Data<Self>, req: HttpRequest, item: web::Json<ResetPasswordRequest>) -> impl Responder {
+        match data.user_service.reset_password(item.into_inner()).await {
+            Ok(user_info) => {
+                data.audit(
+                    AuditEvent::new(AuditEventType::PasswordReset, &client_info(&req))
+                        .target_user(user_info.id),
+                )
+                .await;
+                HttpResponse::NoContent().finish()
+            }
+            Err(err) => match err {
+                WeakPassword(violations) => Self::weak_password(violations),
+                InvalidInput(message) | InvalidCredentials(message) => {
+                    HttpResponse::BadRequest().json(ErrorResponse::new(message))
+                }
+                NotFound(_) => HttpResponse::BadRequest().json(ErrorResponse::new("Invalid or expired reset token")),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    fn weak_password(violations: Vec<PasswordViolation>) -> HttpResponse {
+        HttpResponse::BadRequest().json(WeakPasswordResponse {
+            error: "Password does not meet the password policy".to_string(),
+            violations,
+        })
+    }
+
+    /// With cookie sessions, the tokens are only set as cookies and never exposed to scripts.
+    fn token_response(&self, tokens: TokenResponse) -> HttpResponse {
+        let mut response = HttpResponse::Ok();
+        if self.cookies.enabled {
+            let csrf_token = random_token(32);
+            for cookie in self.cookies.login_cookies(&tokens, &csrf_token) {
+                response.cookie(cookie);
+            }
+            return response.json(CookieSessionResponse {
+                expires_in: tokens.expires_in,
+                scope: tokens.scope,
+                csrf_token,
+            });
+        }
+        response.append_header(
+            (HeaderName::from_static("authorization"),
+            format!("Bearer {}", tokens.access_token)
+        ));
+        response.json(tokens)
+    }
+
+    async fn audit(&self, event: AuditEvent) {
+        audit_sink::record(self.audit.as_ref(), event).await;
+    }
+
+    /// The actor is read back from the issued token, which also covers second-factor and
+    /// external logins where the username was never submitted.
+    async fn audit_login(&self, req: &HttpRequest, tokens: &TokenResponse, method: &str) {
+        let mut event = AuditEvent::new(AuditEventType::LoginSucceeded, &client_info(req)).details(method);
+        let user_id = self.user_service.authenticate(&tokens.access_token).ok().map(|auth| auth.user.id);
+        if let Some(user_id) = user_id {
+            event = event.actor(user_id).target_user(user_id);
+        }
+        self.audit(event).await;
+        if tokens.reactivated
+            && let Some(user_id) = user_id
+        {
+            let event = AuditEvent::new(AuditEventType::AccountReactivated, &client_info(req))
+                .actor(user_id)
+                .target_user(user_id);
+            self.audit(event).await;
+        }
+    }
+
+    /// The target is the account whose username was submitted, when it exists. The username
+    /// itself is not recorded: it may as well be a mistyped password.
+    async fn audit_login_failure(&self, req: &HttpRequest, username: Option<&str>, reason: &str) {
+        let mut event = AuditEvent::new(AuditEventType::LoginFailed, &client_info(req)).details(reason);
+        if let Some(username) = username
+            && let Ok(user_info) = self.user_service.get_user_info(username.to_string()).await
+        {
+            event = event.target_user(user_info.id);
+        }
+        self.audit(event).await;
+    }
+
+    /// Clears the session cookies of a browser that was just signed out.
+    fn signed_out(&self, mut response: HttpResponseBuilder) -> HttpResponseBuilder {
+        if self.cookies.enabled {
+            for cookie in self.cookies.logout_cookies() {
+                response.cookie(cookie);
+            }
+        }
+        response
+    }
+
+    async fn jwks(data: web::Data<Self>) -> impl Responder {
+        HttpResponse::Ok().json(data.user_service.jwks())
+    }
+
+    async fn logout(
+        data: web::Data<Self>,
+        req: HttpRequest,
+        auth: web::ReqData<AuthContext>,
+        token: Option<web::ReqData<BearerToken>>,
+    ) -> impl Responder {
+        let Some(token) = token else {
+            return HttpResponse::BadRequest()
+                .json(ErrorResponse::new("Only bearer token sessions can be logged out"));
+        };
+        match data.user_service.logout(&token.0) {
+            Ok(_) => {
+                data.audit(
+                    AuditEvent::new(AuditEventType::Logout, &client_info(&req))
+                        .actor(auth.user.id)
+                        .target_user(auth.user.id),
+                )
+                .await;
+                data.signed_out(HttpResponse::NoContent()).finish()
+            }
+            Err(err) => match err {
+                InvalidCredentials(_) => HttpResponse::Unauthorized().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    /// Admins get every account in full; other users only the public view of active accounts.
+    async fn list_users(data: web::Data<Self>, auth: web::ReqData<AuthContext>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersRead) {
+            return response;
+        }
+        let result = if auth.has_scope(Permission::UsersAdmin) {
+            data.user_service.get_all_users().await.map(|users| HttpResponse::Ok().json(users))
+        } else {
+            data.user_service.get_public_profiles().await.map(|users| HttpResponse::Ok().json(users))
+        };
+        match result {
+            Ok(response) => response,
+            Err(err) => match err {
+                InvalidInput(_) => HttpResponse::BadRequest().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn get_own_profile(data: web::Data<Self>, auth: web::ReqData<AuthContext>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountRead) {
+            return response;
+        }
+        match data.user_service.get_private_profile(&auth.user).await {
+            Ok(profile) => HttpResponse::Ok().json(profile),
+            Err(err) => match err {
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    /// A user looked up by id or, when the segment is not a number, by username. Admins get any
+    /// account in full; other users only the public view of active accounts.
+    async fn get_user(data: web::Data<Self>, auth: web::ReqData<AuthContext>, user: web::Path<String>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersRead) {
+            return response;
+        }
+        let user = user.into_inner();
+        let result = if auth.has_scope(Permission::UsersAdmin) {
+            match user.parse::<u32>() {
+                Ok(id) => data.user_service.get_user_by_id(id).await,
+                Err(_) => data.user_service.get_user_info(user).await,
+            }
+            .map(|user_info| HttpResponse::Ok().json(user_info))
+        } else {
+            data.user_service.get_public_profile(user).await.map(|profile| HttpResponse::Ok().json(profile))
+        };
+        match result {
+            Ok(response) => response,
             Err(err) => match err {
                 crate::user::Error::NotFound(_) => HttpResponse::NotFound().finish(),
                 InvalidInput(_) => HttpResponse::BadRequest().finish(),
@@ -86,4 +506,454 @@ impl UserRoutes {
             },
         }
     }
+
+    async fn grant_role(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, path: web::Path<(u32, Role)>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        let (id, role) = path.into_inner();
+        match data.user_service.grant_role(id, role).await {
+            Ok(user_info) => {
+                data.audit(
+                    AuditEvent::new(AuditEventType::RoleGranted, &client_info(&req))
+                        .actor(auth.user.id)
+                        .target_user(user_info.id)
+                        .details(role.to_string()),
+                )
+                .await;
+                HttpResponse::Ok().json(user_info)
+            }
+            Err(err) => match err {
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn revoke_role(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, path: web::Path<(u32, Role)>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        let (id, role) = path.into_inner();
+        match data.user_service.revoke_role(id, role).await {
+            Ok(user_info) => {
+                data.audit(
+                    AuditEvent::new(AuditEventType::RoleRevoked, &client_info(&req))
+                        .actor(auth.user.id)
+                        .target_user(user_info.id)
+                        .details(role.to_string()),
+                )
+                .await;
+                HttpResponse::Ok().json(user_info)
+            }
+            Err(err) => match err {
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn audit_log(data: web::Data<Self>, auth: web::ReqData<AuthContext>, query: web::Query<AuditQuery>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        let mut query = query.into_inner();
+        query.limit = Some(query.limit().min(MAX_QUERY_LIMIT));
+        match data.audit.query(&query).await {
+            Ok(events) => HttpResponse::Ok().json(events),
+            Err(err) => {
+                eprintln!("Failed to query audit events: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    /// Everything held about the caller, as one JSON document or as a zip archive with one file
+    /// per section (`?format=zip`).
+    async fn export_data(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, query: web::Query<ExportQuery>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountRead) {
+            return response;
+        }
+        let export = match data.exporter.export(&auth.user).await {
+            Ok(export) => export,
+            Err(err) => {
+                eprintln!("Failed to export personal data of user {}: {:?}", auth.user.id, err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+        let filename = format!("personal-data-{}", auth.user.id);
+        let response = match query.format {
+            ExportFormat::Json => HttpResponse::Ok()
+                .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.json\"", filename)))
+                .json(&export),
+            ExportFormat::Zip => match export.to_zip() {
+                Ok(archive) => HttpResponse::Ok()
+                    .content_type("application/zip")
+                    .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.zip\"", filename)))
+                    .body(archive),
+                Err(err) => {
+                    eprintln!("Failed to export personal data of user {}: {:?}", auth.user.id, err);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            },
+        };
+        data.audit(
+            AuditEvent::new(AuditEventType::PersonalDataExported, &client_info(&req))
+                .actor(auth.user.id)
+                .target_user(auth.user.id),
+        )
+        .await;
+        response
+    }
+
+    async fn update_own_profile(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, item: web::Json<UpdateProfileRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        data.apply_profile_update(&req, &auth, auth.user.id, item.into_inner()).await
+    }
+
+    /// Users may update their own profile; anyone else's requires `users:admin`.
+    async fn update_profile(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, id: web::Path<u32>, item: web::Json<UpdateProfileRequest>) -> impl Responder {
+        let scope = if *id == auth.user.id { Permission::AccountWrite } else { Permission::UsersAdmin };
+        if let Err(response) = require_scope(&auth, scope) {
+            return response;
+        }
+        data.apply_profile_update(&req, &auth, *id, item.into_inner()).await
+    }
+
+    async fn apply_profile_update(&self, req: &HttpRequest, auth: &AuthContext, id: u32, request: UpdateProfileRequest) -> HttpResponse {
+        let previous = match self.user_service.get_user_by_id(id).await {
+            Ok(user_info) => user_info,
+            Err(NotFound(_)) => return HttpResponse::NotFound().finish(),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+        let requested_email = request.email.clone();
+        match self.user_service.update_profile(id, request).await {
+            Ok(profile) => {
+                let client = client_info(req);
+                if profile.user.username != previous.username {
+                    self.audit(
+                        AuditEvent::new(AuditEventType::UsernameChanged, &client)
+                            .actor(auth.user.id)
+                            .target_user(profile.user.id),
+                    )
+                    .await;
+                }
+                if profile.pending_email.is_some() && profile.pending_email == requested_email.map(|e| e.trim().to_string()) {
+                    self.audit(
+                        AuditEvent::new(AuditEventType::EmailChangeRequested, &client)
+                            .actor(auth.user.id)
+                            .target_user(profile.user.id),
+                    )
+                    .await;
+                }
+                HttpResponse::Ok().json(profile)
+            }
+            Err(err) => match err {
+                AlreadyExists(message) => HttpResponse::Conflict().json(ErrorResponse::new(message)),
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn list_api_keys(data: web::Data<Self>, auth: web::ReqData<AuthContext>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountRead) {
+            return response;
+        }
+        match data.user_service.list_api_keys(auth.user.id).await {
+            Ok(keys) => HttpResponse::Ok().json(keys),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    async fn create_api_key(data: web::Data<Self>, auth: web::ReqData<AuthContext>, item: web::Json<CreateApiKeyRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        match data.user_service.create_api_key(&auth.user, item.into_inner()).await {
+            Ok(key) => HttpResponse::Created().json(key),
+            Err(err) => match err {
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn revoke_api_key(data: web::Data<Self>, auth: web::ReqData<AuthContext>, key_id: web::Path<Uuid>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        match data.user_service.revoke_api_key(auth.user.id, *key_id).await {
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(err) => match err {
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn list_sessions(data: web::Data<Self>, auth: web::ReqData<AuthContext>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountRead) {
+            return response;
+        }
+        HttpResponse::Ok().json(data.user_service.list_sessions(auth.user.id, auth.family))
+    }
+
+    async fn list_logins(data: web::Data<Self>, auth: web::ReqData<AuthContext>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountRead) {
+            return response;
+        }
+        match data.user_service.login_history(auth.user.id).await {
+            Ok(attempts) => HttpResponse::Ok().json(attempts),
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    async fn revoke_session(data: web::Data<Self>, auth: web::ReqData<AuthContext>, session_id: web::Path<Uuid>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        match data.user_service.revoke_session(auth.user.id, *session_id) {
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(err) => match err {
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn revoke_user_sessions(data: web::Data<Self>, auth: web::ReqData<AuthContext>, id: web::Path<u32>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        match data.user_service.get_user_by_id(*id).await {
+            Ok(_) => {
+                let revoked = data.user_service.revoke_all_sessions(*id);
+                HttpResponse::Ok().json(serde_json::json!({ "revoked": revoked }))
+            }
+            Err(err) => match err {
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn unlock_user(data: web::Data<Self>, auth: web::ReqData<AuthContext>, id: web::Path<u32>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        match data.user_service.unlock_user(*id).await {
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(err) => match err {
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn change_password(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, item: web::Json<ChangePasswordRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        match data.user_service.change_password(&auth, item.into_inner()).await {
+            Ok(_) => {
+                data.audit(
+                    AuditEvent::new(AuditEventType::PasswordChanged, &client_info(&req))
+                        .actor(auth.user.id)
+                        .target_user(auth.user.id),
+                )
+                .await;
+                HttpResponse::NoContent().finish()
+            }
+            Err(err) => match err {
+                WeakPassword(violations) => Self::weak_password(violations),
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                InvalidCredentials(message) => HttpResponse::Forbidden().json(ErrorResponse::new(message)),
+                TooManyAttempts(retry_after) => HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(ErrorResponse::new("Too many failed attempts, try again later")),
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn enroll_totp(data: web::Data<Self>, auth: web::ReqData<AuthContext>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        match data.user_service.begin_totp_enrollment(auth.user.id).await {
+            Ok(enrollment) => HttpResponse::Ok().json(enrollment),
+            Err(err) => match err {
+                AlreadyExists(message) => HttpResponse::Conflict().json(ErrorResponse::new(message)),
+                NotFound(_) => HttpResponse::NotFound().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn confirm_totp(data: web::Data<Self>, auth: web::ReqData<AuthContext>, item: web::Json<TotpCodeRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        match data.user_service.confirm_totp(auth.user.id, &item.code).await {
+            Ok(codes) => HttpResponse::Ok().json(codes),
+            Err(err) => match err {
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                AlreadyExists(message) => HttpResponse::Conflict().json(ErrorResponse::new(message)),
+                NotFound(message) => HttpResponse::NotFound().json(ErrorResponse::new(message)),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn disable_totp(data: web::Data<Self>, auth: web::ReqData<AuthContext>, item: web::Json<TotpCodeRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        match data.user_service.disable_totp(auth.user.id, &item.code).await {
+            Ok(_) => HttpResponse::NoContent().finish(),
+            Err(err) => match err {
+                InvalidInput(message) => HttpResponse::BadRequest().json(ErrorResponse::new(message)),
+                NotFound(message) => HttpResponse::NotFound().json(ErrorResponse::new(message)),
+                _ => HttpResponse::InternalServerError().finish(),
+            },
+        }
+    }
+
+    async fn deactivate_own_account(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, item: web::Json<ConfirmPasswordRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        if let Err(err) = data.user_service.confirm_account_action(&auth, item.into_inner().password).await {
+            return Self::account_error(err);
+        }
+        match data.user_service.deactivate_user(auth.user.id).await {
+            Ok(user_info) => {
+                data.audit_account(&req, AuditEventType::AccountDeactivated, &auth, user_info.id, None).await;
+                data.signed_out(HttpResponse::NoContent()).finish()
+            }
+            Err(err) => Self::account_error(err),
+        }
+    }
+
+    async fn delete_own_account(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, item: web::Json<ConfirmPasswordRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        if let Err(err) = data.user_service.confirm_account_action(&auth, item.into_inner().password).await {
+            return Self::account_error(err);
+        }
+        match data.user_service.delete_user(auth.user.id).await {
+            Ok(deletion) => {
+                let details = format!("erase after {}", deletion.erase_after.to_rfc3339());
+                data.audit_account(&req, AuditEventType::AccountDeleted, &auth, auth.user.id, Some(details)).await;
+                data.signed_out(HttpResponse::Accepted()).json(deletion)
+            }
+            Err(err) => Self::account_error(err),
+        }
+    }
+
+    async fn erase_own_account(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, item: web::Json<ConfirmPasswordRequest>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::AccountWrite) {
+            return response;
+        }
+        if let Err(err) = data.user_service.confirm_account_action(&auth, item.into_inner().password).await {
+            return Self::account_error(err);
+        }
+        match data.user_service.erase_user(auth.user.id).await {
+            Ok(user_info) => {
+                data.audit_account(&req, AuditEventType::AccountErased, &auth, user_info.id, None).await;
+                data.signed_out(HttpResponse::NoContent()).finish()
+            }
+            Err(err) => Self::account_error(err),
+        }
+    }
+
+    async fn deactivate_user(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, id: web::Path<u32>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        match data.user_service.deactivate_user(*id).await {
+            Ok(user_info) => {
+                data.audit_account(&req, AuditEventType::AccountDeactivated, &auth, user_info.id, None).await;
+                HttpResponse::NoContent().finish()
+            }
+            Err(err) => Self::account_error(err),
+        }
+    }
+
+    async fn reactivate_user(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, id: web::Path<u32>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        match data.user_service.reactivate_user(*id).await {
+            Ok(user_info) => {
+                data.audit_account(&req, AuditEventType::AccountReactivated, &auth, user_info.id, None).await;
+                HttpResponse::NoContent().finish()
+            }
+            Err(err) => Self::account_error(err),
+        }
+    }
+
+    async fn delete_user(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, id: web::Path<u32>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        match data.user_service.delete_user(*id).await {
+            Ok(deletion) => {
+                let details = format!("erase after {}", deletion.erase_after.to_rfc3339());
+                data.audit_account(&req, AuditEventType::AccountDeleted, &auth, *id, Some(details)).await;
+                HttpResponse::Accepted().json(deletion)
+            }
+            Err(err) => Self::account_error(err),
+        }
+    }
+
+    async fn erase_user(data: web::Data<Self>, req: HttpRequest, auth: web::ReqData<AuthContext>, id: web::Path<u32>) -> impl Responder {
+        if let Err(response) = require_scope(&auth, Permission::UsersAdmin) {
+            return response;
+        }
+        match data.user_service.erase_user(*id).await {
+            Ok(user_info) => {
+                data.audit_account(&req, AuditEventType::AccountErased, &auth, user_info.id, None).await;
+                HttpResponse::NoContent().finish()
+            }
+            Err(err) => Self::account_error(err),
+        }
+    }
+
+    async fn audit_account(
+        &self,
+        req: &HttpRequest,
+        event: AuditEventType,
+        auth: &AuthContext,
+        user_id: u32,
+        details: Option<String>,
+    ) {
+        let mut event = AuditEvent::new(event, &client_info(req))
+            .actor(auth.user.id)
+            .target_user(user_id);
+        if let Some(details) = details {
+            event = event.details(details);
+        }
+        self.audit(event).await;
+    }
+
+    /// Errors of the account lifecycle endpoints; `InvalidInput` means the account is not in a
+    /// state allowing the transition.
+    fn account_error(err: Error) -> HttpResponse {
+        match err {
+            InvalidInput(message) => HttpResponse::Conflict().json(ErrorResponse::new(message)),
+            InvalidCredentials(message) => HttpResponse::Forbidden().json(ErrorResponse::new(message)),
+            TooManyAttempts(retry_after) => HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .json(ErrorResponse::new("Too many failed attempts, try again later")),
+            NotFound(_) => HttpResponse::NotFound().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        }
+    }
 }